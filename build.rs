@@ -3,4 +3,5 @@ extern crate gcc;
 fn main() {
     // compile libsph
     gcc::compile_library("libsph_shabal.a", &["lib/shabal.c"]);
+    gcc::compile_library("libguarded_copy.a", &["lib/guarded_copy.c"]);
 }
\ No newline at end of file
@@ -0,0 +1,49 @@
+#include <setjmp.h>
+#include <signal.h>
+#include <stddef.h>
+#include <string.h>
+
+/* Reading a memory mapped file raises SIGBUS when the page can't be delivered, because the
+ * file was truncated or the disk failed. A copy made through rminer_guarded_copy returns -1
+ * instead of taking the whole process down. */
+
+/* volatile, and fenced in rminer_guarded_copy, so the compiler can't move setting and clearing
+ * it across the copy the handler is meant to catch */
+static __thread sigjmp_buf *volatile guard;
+static struct sigaction previous;
+
+static void on_sigbus(int sig, siginfo_t *info, void *context)
+{
+    sigjmp_buf *jump = guard;
+    if (jump) {
+        guard = NULL;
+        siglongjmp(*jump, 1);
+    }
+    /* not one of our copies, let whoever was there before handle it */
+    sigaction(SIGBUS, &previous, NULL);
+    raise(sig);
+}
+
+int rminer_install_sigbus_handler(void)
+{
+    struct sigaction action;
+    memset(&action, 0, sizeof(action));
+    action.sa_sigaction = on_sigbus;
+    action.sa_flags = SA_SIGINFO | SA_NODEFER;
+    sigemptyset(&action.sa_mask);
+    return sigaction(SIGBUS, &action, &previous);
+}
+
+int rminer_guarded_copy(void *dst, const void *src, size_t len)
+{
+    sigjmp_buf jump;
+    if (sigsetjmp(jump, 1)) {
+        return -1;
+    }
+    guard = &jump;
+    __atomic_signal_fence(__ATOMIC_SEQ_CST);
+    memcpy(dst, src, len);
+    __atomic_signal_fence(__ATOMIC_SEQ_CST);
+    guard = NULL;
+    return 0;
+}
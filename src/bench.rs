@@ -0,0 +1,105 @@
+use cli::Args;
+use constants::*;
+use devices;
+use miner::{self, MinerWork, NonceSubmitter, RoundResult};
+use plots;
+use pool;
+use sph_shabal;
+use std::sync::mpsc::{channel, Sender};
+use std::time::Instant;
+
+/// Passes on what every round read, nothing gets submitted.
+#[derive(Clone)]
+struct BenchSubmitter {
+    results: Sender<RoundResult>,
+}
+
+impl NonceSubmitter for BenchSubmitter {
+    fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+        Ok(String::new())
+    }
+
+    fn round_finished(&self, _miner_work: &MinerWork, result: &RoundResult) {
+        let _ = self.results.send(result.clone());
+    }
+}
+
+/// `bench [--config <path>] [--rounds <count>] [options]`
+///
+/// Mines made up blocks with the configured plots, threads and read backends and prints how
+/// fast the scoops were read and hashed. Every round reads another scoop, scoops read before
+/// may still be in the page cache.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse(args, &["rounds"]));
+    let rounds = try!(args.option("rounds", 3u64));
+    let miner_config = try!(args.load_config(&["plot_folders"]));
+    let folder_paths = match miner_config.folder_paths() {
+        Some(folder_paths) => folder_paths,
+        None => return Err(String::from("no plot folders configured")),
+    };
+    let plot_folders = try!(plots::get_usable_plots(folder_paths,
+                                                    &try!(miner_config.scan_options())));
+    let groups = devices::group(&plot_folders,
+                                &miner_config.device_threads.clone().unwrap_or_default());
+    for line in devices::report(&groups) {
+        println!("{}", line);
+    }
+
+    let (results, results_recv) = channel();
+    let senders = miner::spawn(&groups,
+                               BenchSubmitter { results: results },
+                               &miner_config.read_errors());
+    let mut total_nonces = 0;
+    let mut total_secs = 0.0;
+    for round in 0..rounds {
+        let miner_work = bench_work(round);
+        let start = Instant::now();
+        for sender in &senders {
+            try!(sender.send(miner_work).map_err(|_| String::from("a miner thread is gone")));
+        }
+        let mut nonces = 0;
+        for _ in 0..senders.len() {
+            let result = try!(results_recv.recv()
+                .map_err(|_| String::from("the miner threads are gone")));
+            nonces += result.nonces_read;
+        }
+        let elapsed = Instant::now() - start;
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+        println!("round {}: scoop {}, {} nonces in {:.3}s, {}",
+                 round + 1,
+                 miner_work.scoop_num,
+                 nonces,
+                 secs,
+                 speed(nonces, secs));
+        total_nonces += nonces;
+        total_secs += secs;
+    }
+    if rounds > 0 {
+        println!("{} rounds: {}", rounds, speed(total_nonces, total_secs));
+    }
+    Ok(())
+}
+
+/// A block nobody mines, `round` picks its scoop. The target deadline of 0 keeps the miner
+/// threads from submitting.
+fn bench_work(round: u64) -> MinerWork {
+    let signature = sph_shabal::shabal256(format!("rust-miner bench {}", round).as_bytes());
+    let mut hasher = [0u8; 32 + HASH_SIZE * 2];
+    hasher[..32].copy_from_slice(&signature);
+    MinerWork {
+        hasher: hasher,
+        scoop_num: miner::scoop_number(&signature, round),
+        height: round,
+        target_deadline: 0,
+        base_target: 1,
+    }
+}
+
+fn speed(nonces: u64, secs: f64) -> String {
+    if secs <= 0.0 {
+        return String::from("too fast to measure");
+    }
+    format!("{:.0} nonces/s, {:.1} MiB/s",
+            nonces as f64 / secs,
+            (nonces * HASH_SIZE as u64 * 2) as f64 / secs / 1024.0 / 1024.0)
+}
@@ -0,0 +1,107 @@
+use hyper::Url;
+use pool;
+use rustc_serialize::json;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One request to the pool and what came back, as written to the capture file.
+#[derive(RustcDecodable, RustcEncodable, Debug, Clone)]
+pub struct CaptureEntry {
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub request_type: String,
+    /// the full request url with the secret phrase redacted
+    pub request: String,
+    pub status: Option<u16>,
+    pub response: Option<String>,
+    /// set instead of status and response when the request failed before the pool answered
+    pub error: Option<String>,
+}
+
+/// Appends every pool request and response to a capture file, one JSON object per line.
+#[derive(Clone)]
+pub struct Capture {
+    file: Arc<Mutex<File>>,
+}
+
+impl Capture {
+    pub fn open(path: &Path) -> Result<Capture, String> {
+        let file = try!(OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("opening capture file {:?}: {}", path, e)));
+        Ok(Capture { file: Arc::new(Mutex::new(file)) })
+    }
+
+    pub fn record(&self,
+                  request_type: &str,
+                  request_url: &Url,
+                  result: &Result<(u16, String), pool::Error>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000)
+            .unwrap_or(0);
+        let (status, response, error) = match *result {
+            Ok((status, ref response)) => (Some(status), Some(response.clone()), None),
+            Err(ref e) => (None, None, Some(format!("{:?}", e))),
+        };
+        let entry = CaptureEntry {
+            timestamp: timestamp,
+            request_type: String::from(request_type),
+            request: redact(request_url),
+            status: status,
+            response: response,
+            error: error,
+        };
+
+        let line = json::encode(&entry).unwrap();
+        let mut file = self.file.lock().unwrap();
+        if let Err(e) = writeln!(file, "{}", line) {
+            warn!("writing capture: {:?}", e);
+        }
+    }
+
+    /// Makes sure everything recorded so far is on disk.
+    pub fn flush(&self) {
+        if let Err(e) = self.file.lock().unwrap().sync_data() {
+            warn!("flushing capture: {:?}", e);
+        }
+    }
+}
+
+/// The request url with the value of `secretPhrase` replaced.
+pub fn redact(url: &Url) -> String {
+    let mut redacted = url.clone();
+    let pairs: Vec<(String, String)> = url.query_pairs()
+        .map(|(key, value)| {
+            let value = if key == "secretPhrase" {
+                String::from("REDACTED")
+            } else {
+                value.into_owned()
+            };
+            (key.into_owned(), value)
+        })
+        .collect();
+    if !pairs.is_empty() {
+        redacted.query_pairs_mut().clear().extend_pairs(pairs);
+    }
+    redacted.to_string()
+}
+
+pub fn read(path: &Path) -> Result<Vec<CaptureEntry>, String> {
+    let file = try!(File::open(path).map_err(|e| format!("opening {:?}: {}", path, e)));
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("reading {:?}: {}", path, e)));
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(try!(json::decode::<CaptureEntry>(&line)
+            .map_err(|e| format!("{:?} line {}: {}", path, i + 1, e))));
+    }
+    Ok(entries)
+}
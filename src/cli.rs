@@ -0,0 +1,318 @@
+use config::{self, Kind, MinerConfiguration};
+use rustc_serialize::json::Json;
+use std::collections::BTreeMap;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A subcommand, how it is called and what it does.
+struct Command {
+    name: &'static str,
+    synopsis: &'static str,
+    about: &'static str,
+    /// whether it reads the configuration and takes its fields as options
+    configured: bool,
+}
+
+const COMMANDS: &'static [Command] = &[
+    Command {
+        name: "mine",
+        synopsis: "mine [--config <path>] [options]",
+        about: "mine the plots of the configuration, the default command",
+        configured: true,
+    },
+    Command {
+        name: "plot",
+        synopsis: "plot <folder> --account <id> --start <nonce> --nonces <count> \
+                   [--stagger <size>]",
+        about: "write a plot file, the stagger defaults to at most 8192 nonces",
+        configured: false,
+    },
+    Command {
+        name: "verify",
+        synopsis: "verify [--config <path>] [--sample <nonces>] [options] [folder...]",
+        about: "check that plots hold the nonces their names promise, by plotting a few of them \
+                again",
+        configured: true,
+    },
+    Command {
+        name: "info",
+        synopsis: "info [--config <path>] [options]",
+        about: "print the configuration, the plots found and how they are read",
+        configured: true,
+    },
+    Command {
+        name: "config",
+        synopsis: "config dump [--config <path>] [--format json|toml] [options]",
+        about: "print the configuration after the environment and the options, secrets redacted",
+        configured: true,
+    },
+    Command {
+        name: "bench",
+        synopsis: "bench [--config <path>] [--rounds <count>] [options]",
+        about: "read and hash random scoops of all plots like rounds of mining, without a pool",
+        configured: true,
+    },
+    Command {
+        name: "deadline",
+        synopsis: "deadline <generation_signature> <height> <base_target> <account_id> <nonce> \
+                   [plot_file]",
+        about: "compute a deadline from scratch, checking the scoop on disk if a plot is given",
+        configured: false,
+    },
+    Command {
+        name: "replay",
+        synopsis: "replay <mining_info_file> <result_file> [--speed <factor>|max] \
+                   [--config <path>] [options]",
+        about: "mine recorded blocks without a network",
+        configured: true,
+    },
+    Command {
+        name: "mock-pool",
+        synopsis: "mock-pool <capture_file> [--listen <address>]",
+        about: "serve a capture file like the pool that was recorded",
+        configured: false,
+    },
+    Command {
+        name: "help",
+        synopsis: "help [command]",
+        about: "print this help or the help of a command",
+        configured: false,
+    },
+];
+
+/// The arguments of a subcommand.
+#[derive(Debug, Default)]
+pub struct Args {
+    pub config: Option<PathBuf>,
+    /// configuration fields set on the command line
+    pub overrides: BTreeMap<String, Json>,
+    /// the options of the subcommand itself
+    pub options: BTreeMap<String, String>,
+    pub positional: Vec<String>,
+    pub help: bool,
+}
+
+impl Args {
+    /// Parses `--name value` and `--name=value` options, `own_options` are the ones of the
+    /// subcommand, any other name has to be a configuration field with dashes for underscores.
+    ///
+    /// The old single dash form `-name=value` still works.
+    pub fn parse(args: &[String], own_options: &[&str]) -> Result<Args, String> {
+        let mut parsed = Args::default();
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                parsed.positional.extend(args.by_ref().cloned());
+                break;
+            }
+            if arg == "-h" || arg == "--help" {
+                parsed.help = true;
+                continue;
+            }
+            let option = if arg.starts_with("--") {
+                &arg[2..]
+            } else if arg.starts_with('-') && arg.contains('=') {
+                &arg[1..]
+            } else {
+                parsed.positional.push(arg.clone());
+                continue;
+            };
+            let (name, inline_value) = match option.find('=') {
+                Some(i) => (&option[..i], Some(option[i + 1..].to_owned())),
+                None => (option, None),
+            };
+
+            let field = config::field(&name.replace('-', "_"));
+            let value = match inline_value {
+                Some(value) => value,
+                // a flag on its own is switched on
+                None if field.map_or(false, |field| field.kind == Kind::Flag) &&
+                        args.peek().map_or(true, |next| next.starts_with('-')) => {
+                    String::from("true")
+                }
+                None => {
+                    match args.next() {
+                        Some(value) => value.clone(),
+                        None => return Err(format!("--{} needs a value", name)),
+                    }
+                }
+            };
+
+            if name == "config" {
+                parsed.config = Some(PathBuf::from(value));
+            } else if own_options.contains(&name) {
+                parsed.options.insert(name.to_owned(), value);
+            } else if let Some(field) = field {
+                let value = try!(config::parse_value(field, &value));
+                parsed.overrides.insert(field.name.to_owned(), value);
+            } else {
+                return Err(format!("unknown option --{}", name));
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Like `parse` for subcommands that don't read the configuration.
+    pub fn parse_plain(args: &[String], own_options: &[&str]) -> Result<Args, String> {
+        let parsed = try!(Args::parse(args, own_options));
+        if let Some(name) = parsed.overrides.keys().next() {
+            return Err(format!("unknown option --{}", name.replace('_', "-")));
+        }
+        if parsed.config.is_some() {
+            return Err(String::from("unknown option --config"));
+        }
+        Ok(parsed)
+    }
+
+    /// The validated configuration with the `RMINER_*` environment variables and the options of
+    /// the command line on top, `required` are the fields the subcommand can't do without.
+    pub fn load_config(&self, required: &[&str]) -> Result<MinerConfiguration, String> {
+        let environment: Vec<(String, String)> = env::vars().collect();
+        config::load_with(self.config_path(), &environment, &self.overrides, required)
+    }
+
+    /// The config file to read: `--config`, or else `./config.json` or `./config.toml` if one
+    /// of them exists.
+    pub fn config_path(&self) -> Option<&Path> {
+        if let Some(ref path) = self.config {
+            return Some(path.as_path());
+        }
+        ["./config.json", "./config.toml"].iter().map(Path::new).find(|path| path.exists())
+    }
+
+    /// The value of an option of the subcommand, or `default` if it wasn't given.
+    pub fn option<T: FromStr>(&self, name: &str, default: T) -> Result<T, String> {
+        match self.options.get(name) {
+            Some(value) => value.parse().map_err(|_| format!("invalid --{} {}", name, value)),
+            None => Ok(default),
+        }
+    }
+
+    pub fn required<T: FromStr>(&self, name: &str) -> Result<T, String> {
+        match self.options.get(name) {
+            Some(value) => value.parse().map_err(|_| format!("invalid --{} {}", name, value)),
+            None => Err(format!("--{} is missing", name)),
+        }
+    }
+}
+
+/// Whether `name` is a subcommand.
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.iter().any(|command| command.name == name)
+}
+
+/// The help of one command, or the overview of all of them.
+pub fn usage(command: Option<&str>) -> String {
+    let command = match command.and_then(|name| COMMANDS.iter().find(|c| c.name == name)) {
+        Some(command) => command,
+        None => {
+            let mut usage = String::from("usage: rust-miner <command> [options]\n\ncommands:\n");
+            for command in COMMANDS {
+                usage.push_str(&format!("  {:<10} {}\n", command.name, command.about));
+            }
+            usage.push_str("\nrun `rust-miner help <command>` for the options of a command\n");
+            return usage;
+        }
+    };
+
+    let mut usage = format!("usage: rust-miner {}\n\n{}\n", command.synopsis, command.about);
+    if command.configured {
+        usage.push_str("\nevery field of the configuration can be set as an option, lists and \
+                        tables are comma\nseparated, e.g. --plot-folders /mnt/a,/mnt/b \
+                        --device-threads md0=4, or as an\nenvironment variable like \
+                        RMINER_PLOT_FOLDERS, the options win:\n");
+        for field in config::FIELDS {
+            let option = format!("--{} <{}>", field.name.replace('_', "-"), kind_name(field.kind));
+            usage.push_str(&format!("  {:<40} {}\n", option, field.help));
+        }
+    }
+    usage
+}
+
+fn kind_name(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Text => "text",
+        Kind::Number => "number",
+        Kind::Flag => "true|false",
+        Kind::List | Kind::Folders => "list",
+        Kind::NumberTable | Kind::TextTable => "name=value,...",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::json::Json;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::PathBuf;
+    use testing::TempDir;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn parses_options_overrides_and_positionals() {
+        let parsed = Args::parse(&args(&["plots",
+                                          "--config",
+                                          "miner.json",
+                                          "--sample=8",
+                                          "--exclude-overlaps",
+                                          "--plot-folders",
+                                          "/mnt/a, /mnt/b",
+                                          "--device-threads=md0=4",
+                                          "-rescan_interval=0"]),
+                                 &["sample"])
+            .unwrap();
+        assert_eq!(parsed.positional, vec!["plots"]);
+        assert_eq!(parsed.config, Some(PathBuf::from("miner.json")));
+        assert_eq!(parsed.option("sample", 4u64), Ok(8));
+        assert_eq!(parsed.overrides["exclude_overlaps"], Json::Boolean(true));
+        assert_eq!(parsed.overrides["plot_folders"],
+                   Json::from_str("[\"/mnt/a\", \"/mnt/b\"]").unwrap());
+        assert_eq!(parsed.overrides["device_threads"],
+                   Json::from_str("{\"md0\": 4}").unwrap());
+        assert_eq!(parsed.overrides["rescan_interval"], Json::U64(0));
+
+        match parsed.load_config(&[]) {
+            Err(e) => assert!(e.contains("miner.json"), "{}", e),
+            Ok(_) => panic!("miner.json doesn't exist"),
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_and_invalid_options() {
+        assert_eq!(Args::parse(&args(&["--no-such-thing", "1"]), &[]).unwrap_err(),
+                   "unknown option --no-such-thing");
+        assert_eq!(Args::parse(&args(&["--scan-depth", "deep"]), &[]).unwrap_err(),
+                   "scan_depth: expected a number, got \"deep\"");
+        assert_eq!(Args::parse(&args(&["--pool-url"]), &[]).unwrap_err(),
+                   "--pool-url needs a value");
+    }
+
+    #[test]
+    fn overrides_apply_on_top_of_the_config_file() {
+        let dir = TempDir::new("cli");
+        let config_path = dir.join("config.json");
+        File::create(&config_path)
+            .unwrap()
+            .write_all(concat!("{\"pool_url\": \"http://pool.burst-team.us\", ",
+                               "\"plot_folders\": [\"plots\"]}")
+                .as_bytes())
+            .unwrap();
+        let parsed = Args::parse(&args(&["--config",
+                                          config_path.to_str().unwrap(),
+                                          "--pool-url",
+                                          "http://localhost:8124",
+                                          "--read-retries",
+                                          "5"]),
+                                 &[])
+            .unwrap();
+        let config = parsed.load_config(&["pool_url"]).ok().unwrap();
+        assert_eq!(config.pool_url, Some(String::from("http://localhost:8124")));
+        assert_eq!(config.read_retries, Some(5));
+        assert_eq!(config.folder_paths(), Some(vec![String::from("plots")]));
+    }
+}
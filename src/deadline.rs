@@ -0,0 +1,76 @@
+use constants::*;
+use miner;
+use plots::{self, Plot};
+use rustc_serialize::hex::FromHex;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// `deadline <generation_signature> <height> <base_target> <account_id> <nonce> [plot_file]`
+///
+/// Recomputes a deadline from scratch so it can be compared with what the pool reports. When a
+/// plot file is given the scoop stored on disk is checked against the freshly plotted one.
+pub fn run(args: &[String]) -> Result<(), String> {
+    if args.len() != 5 && args.len() != 6 {
+        return Err(String::from("expected <generation_signature> <height> <base_target> \
+                                 <account_id> <nonce> [plot_file]"));
+    }
+    let sig = try!(args[0].from_hex().map_err(|e| format!("generation signature: {}", e)));
+    if sig.len() != 32 {
+        return Err(String::from("generation signature: expected 32 bytes"));
+    }
+    let height = try!(parse_u64("height", &args[1]));
+    let base_target = try!(parse_u64("base target", &args[2]));
+    let account_id = try!(parse_u64("account id", &args[3]));
+    let nonce = try!(parse_u64("nonce", &args[4]));
+    if base_target == 0 {
+        return Err(String::from("base target: must not be 0"));
+    }
+
+    let scoop_num = miner::scoop_number(&sig, height);
+    let hash = miner::nonce_hash(&sig, height, account_id, nonce);
+
+    println!("scoop:    {}", scoop_num);
+    println!("hash:     {}", hash);
+    println!("deadline: {}s",
+             miner::nonce_deadline(&sig, height, base_target, account_id, nonce));
+
+    if args.len() == 6 {
+        let plot = match try!(Plot::from_path(Path::new(&args[5]))) {
+            Some(plot) => plot,
+            None => return Err(format!("{} is not named like a plot file", args[5])),
+        };
+        if plot.account_id != account_id {
+            return Err(format!("plot file belongs to account {}", plot.account_id));
+        }
+        let offset = match plot.scoop_offset(nonce, scoop_num) {
+            Some(offset) => offset,
+            None => return Err(format!("nonce {} is not in {:?}", nonce, plot.path)),
+        };
+
+        let nonce_data = plots::plot_nonce(account_id, nonce);
+        let scoop = &nonce_data[scoop_num as usize * HASH_SIZE * 2..(scoop_num as usize + 1) *
+                                                                     HASH_SIZE *
+                                                                     2];
+        let mut on_disk = [0u8; HASH_SIZE * 2];
+        try!(File::open(&plot.path)
+            .and_then(|mut file| {
+                try!(file.seek(SeekFrom::Start(offset)));
+                file.read_exact(&mut on_disk)
+            })
+            .map_err(|e| format!("reading {:?}: {}", plot.path, e)));
+
+        if &on_disk[..] == scoop {
+            println!("plot:     scoop matches at offset {}", offset);
+        } else {
+            println!("plot:     scoop MISMATCH at offset {}, deadline from disk: {}s",
+                     offset,
+                     miner::scoop_hash(&sig, &on_disk) / base_target);
+        }
+    }
+    Ok(())
+}
+
+fn parse_u64(name: &str, value: &str) -> Result<u64, String> {
+    value.parse::<u64>().map_err(|e| format!("{}: {}", name, e))
+}
@@ -0,0 +1,216 @@
+use constants::*;
+use plots::{Plot, PlotFolders};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// The plots one miner thread reads, all of them on the same device.
+pub type MinerGroups = BTreeMap<(String, u32), Vec<Plot>>;
+
+/// Splits the plots of all folders into one group per miner thread.
+///
+/// Plots are grouped by the block device they are stored on, so two folders on one disk don't
+/// fight over its head. Every device gets one thread unless `device_threads` or the
+/// `read_threads` of one of its folders asks for more, e.g. for a RAID or an SSD, and the plots
+/// of a device are spread evenly over its threads. Every thread reads the plots of folders with
+/// a higher priority first.
+pub fn group(plot_folders: &PlotFolders, device_threads: &BTreeMap<String, u32>) -> MinerGroups {
+    let mut names: BTreeMap<u64, String> = BTreeMap::new();
+    group_by(plot_folders,
+             device_threads,
+             |dev| names.entry(dev).or_insert_with(|| device_name(dev)).clone())
+}
+
+fn group_by<F>(plot_folders: &PlotFolders,
+               device_threads: &BTreeMap<String, u32>,
+               mut name_of: F)
+               -> MinerGroups
+    where F: FnMut(u64) -> String
+{
+    let mut devices: BTreeMap<String, Vec<Plot>> = BTreeMap::new();
+    let mut folder_threads: BTreeMap<String, u32> = BTreeMap::new();
+    for folder in &plot_folders.folders {
+        for plot in &folder.plots {
+            let device = match plot.file_id {
+                Some((dev, _)) => name_of(dev),
+                None => String::from("unknown"),
+            };
+            if let Some(threads) = folder.read_threads {
+                let most = folder_threads.entry(device.clone()).or_insert(threads);
+                *most = (*most).max(threads);
+            }
+            devices.entry(device).or_insert_with(Vec::new).push(plot.clone());
+        }
+    }
+
+    let mut groups = BTreeMap::new();
+    for (device, mut plots) in devices {
+        // the setting of the device wins over what its folders ask for
+        let threads = device_threads.get(&device)
+            .or(folder_threads.get(&device))
+            .cloned()
+            .unwrap_or(1)
+            .max(1);
+        // biggest plots first, each to the thread with the least to read so far
+        plots.sort_by(|a, b| b.usable_nonces().cmp(&a.usable_nonces()));
+        let mut split: Vec<(u64, Vec<Plot>)> = (0..threads).map(|_| (0, Vec::new())).collect();
+        for plot in plots {
+            let lightest = split.iter_mut().min_by_key(|&&mut (nonces, _)| nonces).unwrap();
+            lightest.0 += plot.usable_nonces();
+            lightest.1.push(plot);
+        }
+        for (i, (_, mut thread_plots)) in split.into_iter().enumerate() {
+            if thread_plots.is_empty() {
+                continue;
+            }
+            // keep the mining order of every thread stable between scans
+            thread_plots.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.path.cmp(&b.path)));
+            groups.insert((device.clone(), i as u32), thread_plots);
+        }
+    }
+    groups
+}
+
+/// Which device every miner thread reads, one line per thread.
+pub fn report(groups: &MinerGroups) -> Vec<String> {
+    groups.iter()
+        .map(|(&(ref device, thread), plots)| {
+            let nonces: u64 = plots.iter().map(|plot| plot.usable_nonces()).sum();
+            format!("device {} thread {}: {} plots, {} GiB",
+                    device,
+                    thread,
+                    plots.len(),
+                    nonces * PLOT_SIZE as u64 / 1024 / 1024 / 1024)
+        })
+        .collect()
+}
+
+/// The name of the disk under `/sys/block` a file system lives on, e.g. `sda` for `/dev/sda1`.
+///
+/// Devices sysfs doesn't know, like network or overlay file systems, are named by their
+/// `major:minor` number.
+pub fn device_name(dev: u64) -> String {
+    let number = format!("{}:{}", major(dev), minor(dev));
+    let sys_path = match fs::canonicalize(PathBuf::from("/sys/dev/block").join(&number)) {
+        Ok(sys_path) => sys_path,
+        Err(_) => return number,
+    };
+    // a partition is a subfolder of its disk
+    let disk = match sys_path.join("partition").exists() {
+        true => sys_path.parent().map(|parent| parent.to_path_buf()),
+        false => Some(sys_path),
+    };
+    disk.as_ref()
+        .and_then(|disk| disk.file_name())
+        .and_then(|name| name.to_str())
+        .map(String::from)
+        .unwrap_or(number)
+}
+
+/// Major number of a `st_dev`, in the encoding glibc uses.
+fn major(dev: u64) -> u64 {
+    ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff)
+}
+
+fn minor(dev: u64) -> u64 {
+    (dev & 0xff) | ((dev >> 12) & 0xffff_ff00)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots::{Plot, PlotFolder, PlotFolders};
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    fn plot(name: &str, dev: Option<u64>) -> Plot {
+        let mut plot = Plot::from_path(Path::new(name)).unwrap().unwrap();
+        plot.file_id = dev.map(|dev| (dev, 1));
+        plot
+    }
+
+    fn names(plots: &[Plot]) -> Vec<&str> {
+        plots.iter().map(|plot| plot.path.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn decodes_device_numbers() {
+        // /dev/sda1 and /dev/nvme0n1p2 as the kernel hands them out
+        assert_eq!((major(0x801), minor(0x801)), (8, 1));
+        assert_eq!((major(0x10302), minor(0x10302)), (259, 2));
+        // the bits above the minor number belong to the major number
+        let dev = (0xabcde << 44) | (0x12345 << 20) | (0x678 << 8) | 0x9a;
+        assert_eq!((major(dev), minor(dev)), (0xabcde678, 0x123459a));
+    }
+
+    #[test]
+    fn groups_plots_by_device() {
+        let plot_folders = PlotFolders {
+            folders: vec![PlotFolder {
+                              path: PathBuf::from("/a"),
+                              plots: vec![plot("/a/1_0_8_8", Some(1)),
+                                          plot("/a/1_8_16_8", Some(2)),
+                                          plot("/a/1_24_8_8", None)],
+                              entries: Vec::new(),
+                              notes: Vec::new(),
+                              read_threads: None,
+                          },
+                          PlotFolder {
+                              path: PathBuf::from("/b"),
+                              plots: vec![plot("/b/1_32_8_8", Some(1)),
+                                          plot("/b/1_40_24_8", Some(2)),
+                                          plot("/b/1_64_8_8", Some(2))],
+                              entries: Vec::new(),
+                              notes: Vec::new(),
+                              read_threads: None,
+                          }],
+        };
+        let mut device_threads = BTreeMap::new();
+        device_threads.insert(String::from("md0"), 2);
+
+        let groups = group_by(&plot_folders, &device_threads, |dev| {
+            String::from(if dev == 1 { "sda" } else { "md0" })
+        });
+        let keys: Vec<(&str, u32)> = groups.keys().map(|&(ref d, t)| (d.as_str(), t)).collect();
+        assert_eq!(keys, vec![("md0", 0), ("md0", 1), ("sda", 0), ("unknown", 0)]);
+        assert_eq!(names(&groups[&(String::from("sda"), 0)]),
+                   vec!["/a/1_0_8_8", "/b/1_32_8_8"]);
+        assert_eq!(names(&groups[&(String::from("md0"), 0)]), vec!["/b/1_40_24_8"]);
+        assert_eq!(names(&groups[&(String::from("md0"), 1)]),
+                   vec!["/a/1_8_16_8", "/b/1_64_8_8"]);
+    }
+
+    #[test]
+    fn folders_add_threads_and_go_first() {
+        let mut urgent = vec![plot("/b/1_16_8_8", Some(1)), plot("/b/1_24_8_8", Some(1))];
+        for plot in &mut urgent {
+            plot.priority = 5;
+        }
+        let plot_folders = PlotFolders {
+            folders: vec![PlotFolder {
+                              path: PathBuf::from("/a"),
+                              plots: vec![plot("/a/1_0_8_8", Some(1)), plot("/a/1_8_8_8", Some(1))],
+                              entries: Vec::new(),
+                              notes: Vec::new(),
+                              read_threads: None,
+                          },
+                          PlotFolder {
+                              path: PathBuf::from("/b"),
+                              plots: urgent,
+                              entries: Vec::new(),
+                              notes: Vec::new(),
+                              read_threads: Some(2),
+                          }],
+        };
+        let groups = group_by(&plot_folders, &BTreeMap::new(), |_| String::from("sda"));
+        assert_eq!(groups.len(), 2);
+        assert_eq!(names(&groups[&(String::from("sda"), 0)]), vec!["/b/1_16_8_8", "/a/1_0_8_8"]);
+        assert_eq!(names(&groups[&(String::from("sda"), 1)]), vec!["/b/1_24_8_8", "/a/1_8_8_8"]);
+
+        // device_threads is set for the disk, also to fewer threads than a folder asks for
+        let mut device_threads = BTreeMap::new();
+        device_threads.insert(String::from("sda"), 1);
+        let groups = group_by(&plot_folders, &device_threads, |_| String::from("sda"));
+        assert_eq!(groups.len(), 1);
+    }
+}
@@ -0,0 +1,30 @@
+use cli::Args;
+use config;
+use rustc_serialize::json::{self, Json};
+
+/// `config dump [--config <path>] [--format json|toml] [options]`
+///
+/// Prints the configuration the miner would run with, after the environment and the command
+/// line, with the secrets redacted. It is printed in the format of the config file unless
+/// `--format` says otherwise.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse(args, &["format"]));
+    match args.positional.first().map(|action| action.as_str()) {
+        Some("dump") if args.positional.len() == 1 => {}
+        Some("dump") => return Err(String::from("dump takes no arguments")),
+        Some(action) => return Err(format!("unknown action {}", action)),
+        None => return Err(String::from("expected an action, dump")),
+    }
+    let default_format = match args.config_path() {
+        Some(path) if config::is_toml(path) => "toml",
+        _ => "json",
+    };
+    let format = try!(args.option("format", String::from(default_format)));
+    let fields = try!(args.load_config(&[])).redacted();
+    match format.as_str() {
+        "json" => println!("{}", json::as_pretty_json(&Json::Object(fields))),
+        "toml" => print!("{}", config::to_toml(&fields)),
+        _ => return Err(format!("invalid --format {}, expected json or toml", format)),
+    }
+    Ok(())
+}
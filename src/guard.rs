@@ -0,0 +1,30 @@
+use libc;
+use std::sync::Once;
+
+static INSTALL_HANDLER: Once = Once::new();
+
+/// Copies `src`, a slice of a memory mapped file, into `dst`.
+///
+/// Returns false instead of crashing with SIGBUS when the pages can't be read, because the file
+/// was truncated under the mapping or the disk failed.
+pub fn copy_from_mapping(dst: &mut [u8], src: &[u8]) -> bool {
+    assert_eq!(dst.len(), src.len());
+    INSTALL_HANDLER.call_once(|| {
+        if unsafe { rminer_install_sigbus_handler() } != 0 {
+            panic!("installing the SIGBUS handler failed");
+        }
+    });
+    unsafe {
+        rminer_guarded_copy(dst.as_mut_ptr() as *mut libc::c_void,
+                            src.as_ptr() as *const libc::c_void,
+                            src.len()) == 0
+    }
+}
+
+extern "C" {
+    fn rminer_install_sigbus_handler() -> libc::c_int;
+    fn rminer_guarded_copy(dst: *mut libc::c_void,
+                           src: *const libc::c_void,
+                           len: libc::size_t)
+                           -> libc::c_int;
+}
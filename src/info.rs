@@ -0,0 +1,43 @@
+use cli::Args;
+use devices;
+use overlap;
+use plots;
+use rustc_serialize::json::{self, Json};
+
+/// `info [--config <path>] [options]`
+///
+/// Prints the configuration as the miner would use it, the plots it would find and which
+/// thread would read them, without mining.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse(args, &[]));
+    let miner_config = try!(args.load_config(&[]));
+    println!("configuration:\n{}",
+             json::as_pretty_json(&Json::Object(miner_config.redacted())));
+
+    let folder_paths = match miner_config.folder_paths() {
+        Some(folder_paths) => folder_paths,
+        None => {
+            println!("no plot folders configured");
+            return Ok(());
+        }
+    };
+    let scan_options = try!(miner_config.scan_options());
+    let plot_folders = plots::get_plots(folder_paths, &scan_options);
+    for line in plot_folders.report() {
+        println!("{}", line);
+    }
+    for line in overlap::analyze(&plot_folders).report() {
+        println!("{}", line);
+    }
+    for plot in plot_folders.folders.iter().flat_map(|folder| folder.plots.iter()) {
+        println!("{:?}: {} usable nonces, read through {:?}",
+                 plot.path,
+                 plot.usable_nonces(),
+                 plot.read_backend);
+    }
+    let groups = devices::group(&plot_folders, &miner_config.device_threads.unwrap_or_default());
+    for line in devices::report(&groups) {
+        println!("{}", line);
+    }
+    Ok(())
+}
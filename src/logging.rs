@@ -0,0 +1,351 @@
+use log::{self, Log, LogLevel, LogLevelFilter, LogMetadata, LogRecord, MaxLogLevelFilter};
+use rustc_serialize::json;
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rotated log files kept besides the current one, `miner.log.1` is the newest.
+const KEPT_FILES: u32 = 4;
+
+/// Which records are logged: a level for everything and levels for single targets.
+///
+/// Written like `info,pool=debug,nonce=trace`. A target is a module of the miner, like `main`,
+/// `pool`, `miner` or `rescan`, or `nonce` for a line about every nonce hashed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    level: LogLevelFilter,
+    /// the longest target first, so the most specific one wins
+    targets: Vec<(String, LogLevelFilter)>,
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            level: LogLevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let mut parts = directive.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(target), Some(level)) => {
+                    filter.targets.push((target.trim().to_owned(), try!(parse_level(level))))
+                }
+                (Some(level), None) => filter.level = try!(parse_level(level)),
+                (None, _) => unreachable!("splitn yields at least one part"),
+            }
+        }
+        filter.targets.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+        Ok(filter)
+    }
+
+    fn level(&self, target: &str) -> LogLevelFilter {
+        let target = short_target(target);
+        self.targets
+            .iter()
+            .find(|&&(ref name, _)| {
+                target == name ||
+                (target.starts_with(name.as_str()) && target[name.len()..].starts_with("::"))
+            })
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    /// The most verbose level anything is logged at.
+    fn max(&self) -> LogLevelFilter {
+        self.targets.iter().map(|&(_, level)| level).fold(self.level, cmp::max)
+    }
+}
+
+fn parse_level(level: &str) -> Result<LogLevelFilter, String> {
+    level.trim().parse().map_err(|_| {
+        format!("unknown level {:?}, expected off, error, warn, info, debug or trace",
+                level.trim())
+    })
+}
+
+/// `target` without the name of the crate, the target of `rust_miner::pool` is `pool` and the
+/// one of `rust_miner` itself `main`.
+fn short_target(target: &str) -> &str {
+    let crate_name = module_path!().split("::").next().unwrap();
+    if target == crate_name {
+        "main"
+    } else if target.starts_with(crate_name) && target[crate_name.len()..].starts_with("::") {
+        &target[crate_name.len() + 2..]
+    } else {
+        target
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    /// one JSON object per line, with the time, level, target and message
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, String> {
+        match name {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("expected text or json, got {:?}", name)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub filter: Filter,
+    pub format: Format,
+    /// written to instead of stdout
+    pub file: Option<PathBuf>,
+    /// bytes the file grows to before it is rotated
+    pub file_size: u64,
+}
+
+impl Default for LogSettings {
+    fn default() -> LogSettings {
+        LogSettings {
+            filter: Filter::parse("info").unwrap(),
+            format: Format::Text,
+            file: None,
+            file_size: 16 * 1024 * 1024,
+        }
+    }
+}
+
+/// A log file that is moved aside once it would grow over `max_size`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path, max_size: u64) -> Result<RotatingFile, String> {
+        let file = try!(append(path).map_err(|e| format!("log file {:?}: {}", path, e)));
+        let size = try!(file.metadata().map_err(|e| format!("log file {:?}: {}", path, e)))
+            .len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file: file,
+            size: size,
+            max_size: max_size,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_size {
+            try!(self.rotate());
+        }
+        try!(writeln!(self.file, "{}", line));
+        self.size += len;
+        Ok(())
+    }
+
+    /// Moves `miner.log` to `miner.log.1`, that one to `miner.log.2` and so on, the oldest file
+    /// is overwritten.
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..KEPT_FILES).rev() {
+            let _ = fs::rename(numbered(&self.path, n), numbered(&self.path, n + 1));
+        }
+        try!(fs::rename(&self.path, numbered(&self.path, 1)));
+        self.file = try!(append(&self.path));
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+enum Output {
+    Stdout,
+    File(RotatingFile),
+}
+
+struct State {
+    settings: LogSettings,
+    output: Output,
+}
+
+struct Logger {
+    state: Arc<Mutex<State>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.state.lock().unwrap().settings.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &LogRecord) {
+        let mut state = self.state.lock().unwrap();
+        if record.level() > state.settings.filter.level(record.target()) {
+            return;
+        }
+        let line = format_line(state.settings.format,
+                               SystemTime::now(),
+                               record.level(),
+                               short_target(record.target()),
+                               &record.args().to_string());
+        // the lock keeps lines of different threads apart
+        let written = match state.output {
+            Output::Stdout => writeln!(io::stdout(), "{}", line),
+            Output::File(ref mut file) => file.write_line(&line),
+        };
+        if let Err(e) = written {
+            let _ = writeln!(io::stderr(), "writing the log: {}", e);
+        }
+    }
+}
+
+fn format_line(format: Format,
+               time: SystemTime,
+               level: LogLevel,
+               target: &str,
+               message: &str)
+               -> String {
+    let time = timestamp(time);
+    match format {
+        Format::Text => format!("{} {:<5} {}: {}", time, level, target, message),
+        Format::Json => {
+            format!("{{\"time\":{},\"level\":{},\"target\":{},\"message\":{}}}",
+                    json::encode(&time).unwrap(),
+                    json::encode(&level.to_string()).unwrap(),
+                    json::encode(&target).unwrap(),
+                    json::encode(&message).unwrap())
+        }
+    }
+}
+
+/// `time` in UTC like `2016-11-20T14:03:51.250Z`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    // the days since 1970 to a date, after Howard Hinnant's `civil_from_days`
+    let days = secs / 86400 + 719468;
+    let era = days / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 { month_from_march + 3 } else { month_from_march - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs % 86400 / 3600,
+            secs % 3600 / 60,
+            secs % 60,
+            since_epoch.subsec_nanos() / 1_000_000)
+}
+
+/// The installed logger, to change what it logs and where while the miner runs.
+pub struct Logging {
+    state: Arc<Mutex<State>>,
+    max_level: MaxLogLevelFilter,
+}
+
+/// Installs the logger with the default settings: `info` and above as text on stdout.
+pub fn init() -> Logging {
+    let state = Arc::new(Mutex::new(State {
+        settings: LogSettings::default(),
+        output: Output::Stdout,
+    }));
+    let logger_state = state.clone();
+    let mut max_level = None;
+    log::set_logger(|max| {
+            max.set(LogLevelFilter::Info);
+            max_level = Some(max);
+            Box::new(Logger { state: logger_state })
+        })
+        .expect("the logger is only installed once");
+    Logging {
+        state: state,
+        max_level: max_level.unwrap(),
+    }
+}
+
+impl Logging {
+    /// Switches to `settings`, the file is only opened again when its path or size changed.
+    pub fn apply(&self, settings: LogSettings) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap();
+        if settings.file != state.settings.file || settings.file_size != state.settings.file_size {
+            state.output = match settings.file {
+                Some(ref path) => Output::File(try!(RotatingFile::open(path, settings.file_size))),
+                None => Output::Stdout,
+            };
+        }
+        self.max_level.set(settings.filter.max());
+        state.settings = settings;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::{LogLevel, LogLevelFilter};
+    use std::fs::File;
+    use std::io::Read;
+    use std::time::{Duration, UNIX_EPOCH};
+    use testing::TempDir;
+
+    #[test]
+    fn filters_by_the_most_specific_target() {
+        let filter = Filter::parse("warn, pool=debug,pool::submit=off,nonce=trace").unwrap();
+        assert_eq!(filter.level("rust_miner"), LogLevelFilter::Warn);
+        assert_eq!(filter.level("rust_miner::miner"), LogLevelFilter::Warn);
+        assert_eq!(filter.level("rust_miner::pool"), LogLevelFilter::Debug);
+        assert_eq!(filter.level("rust_miner::pool::submit"), LogLevelFilter::Off);
+        assert_eq!(filter.level("rust_miner::poolside"), LogLevelFilter::Warn);
+        assert_eq!(filter.level("nonce"), LogLevelFilter::Trace);
+        assert_eq!(filter.max(), LogLevelFilter::Trace);
+        assert_eq!(Filter::parse("info,pool=loud").unwrap_err(),
+                   "unknown level \"loud\", expected off, error, warn, info, debug or trace");
+    }
+
+    #[test]
+    fn formats_text_and_json_lines() {
+        let time = UNIX_EPOCH + Duration::new(1479650631, 250_000_000);
+        assert_eq!(format_line(Format::Text, time, LogLevel::Warn, "pool", "try 0 failed"),
+                   "2016-11-20T14:03:51.250Z WARN  pool: try 0 failed");
+        assert_eq!(format_line(Format::Json, time, LogLevel::Info, "miner", "found \"6\""),
+                   "{\"time\":\"2016-11-20T14:03:51.250Z\",\"level\":\"INFO\",\
+                    \"target\":\"miner\",\"message\":\"found \\\"6\\\"\"}");
+        assert_eq!(timestamp(UNIX_EPOCH + Duration::from_secs(951782400)),
+                   "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn rotates_the_file_once_it_is_full() {
+        let dir = TempDir::new("log");
+        let path = dir.join("miner.log");
+        let mut file = RotatingFile::open(&path, 20).unwrap();
+        for line in &["first line", "second line", "third line"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |path: PathBuf| {
+            let mut content = String::new();
+            File::open(path).unwrap().read_to_string(&mut content).unwrap();
+            content
+        };
+        assert_eq!(read(path.clone()), "third line\n");
+        assert_eq!(read(numbered(&path, 1)), "second line\n");
+        assert_eq!(read(numbered(&path, 2)), "first line\n");
+    }
+}
@@ -1,81 +1,377 @@
 extern crate byteorder;
+extern crate glob;
 extern crate regex;
 extern crate rustc_serialize;
 extern crate libc;
 extern crate hyper;
+extern crate toml;
+#[macro_use]
+extern crate log;
 
+mod bench;
+mod capture;
+mod cli;
 mod config;
 mod constants;
+mod deadline;
+mod devices;
+mod dump;
+mod guard;
+mod info;
+mod logging;
 mod miner;
+mod mock_pool;
+mod notify;
+mod overlap;
 mod plots;
+mod plotter;
 mod pool;
+mod read_errors;
+mod reload;
+mod reader;
+mod replay;
+mod rescan;
+mod shutdown;
 mod sph_shabal;
+mod supervisor;
+#[cfg(test)]
+mod testing;
+mod throttle;
+mod uring;
+mod verify;
 
+use capture::Capture;
+use config::MinerConfiguration;
 use hyper::Url;
-use pool::Pool;
-use regex::Regex;
-use rustc_serialize::json;
-use std::cmp::Ordering;
+use logging::Logging;
+use notify::{Notifier, Progress};
+use pool::{Pool, PoolSettings};
+use read_errors::ReadErrors;
+use rescan::{Rescan, RescanSettings};
 use std::env;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-use std::sync::mpsc::channel;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Sender};
 use std::thread;
 use std::time::Duration;
 
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let res: Result<PathBuf, &str> = match args.len().cmp(&1) {
-        Ordering::Greater => {
-            let re = Regex::new(r"^-config=(.*)").unwrap();
-            match re.captures(&args[1]) {
-                Some(captures) => Ok(PathBuf::from(&captures[1])),
-                None => Err("Failed to find path"),
-            }
+    let args: Vec<String> = env::args().skip(1).collect();
+    // without a command the miner mines, like it always did
+    let (command, args) = match args.first() {
+        Some(first) if !first.starts_with('-') => (first.clone(), &args[1..]),
+        _ => (String::from("mine"), &args[..]),
+    };
+    if args.iter().any(|arg| arg == "--help" || arg == "-h") {
+        print!("{}", cli::usage(Some(&command)));
+        return;
+    }
+
+    let logging = logging::init();
+    let res = match command.as_str() {
+        "mine" => mine(args, &logging),
+        "plot" => plotter::run(args),
+        "verify" => verify::run(args),
+        "info" => info::run(args),
+        "config" => dump::run(args),
+        "bench" => bench::run(args),
+        "deadline" => deadline::run(args),
+        "replay" => replay::run(args),
+        "mock-pool" => mock_pool::run(args),
+        "help" => {
+            print!("{}", cli::usage(args.first().map(|arg| arg.as_str())));
+            Ok(())
         }
-        _ => Ok(PathBuf::from("./config.json")),
+        _ => Err(String::from("unknown command")),
     };
-    let config_path = res.unwrap();
-    if !config_path.exists() {
-        usage();
+    if let Err(e) = res {
+        // stdout is for what a command reports, scripts tell errors apart by the stream
+        let mut stderr = io::stderr();
+        let _ = writeln!(stderr, "{}: {}", command, e);
+        let _ = match cli::is_command(&command) {
+            true => writeln!(stderr, "run `rust-miner help {}` for its usage", command),
+            false => writeln!(stderr, "run `rust-miner help` for the list of commands"),
+        };
         std::process::exit(1);
     }
+}
 
-    let mut config_file = File::open(config_path).unwrap();
+/// `mine [--config <path>] [options]`
+fn mine(args: &[String], logging: &Logging) -> Result<(), String> {
+    let args = try!(cli::Args::parse(args, &[]));
+    if let Some(arg) = args.positional.first() {
+        return Err(format!("unexpected argument {}", arg));
+    }
+    let miner_config = try!(args.load_config(&["pool_url", "plot_folders"]));
+    try!(logging.apply(try!(miner_config.log_settings())));
+    if miner_config.plot_buffer_size.is_some() {
+        warn!("plot_buffer_size is not used anymore and can be removed from the configuration");
+    }
+    info!("pool_url: {:?}", miner_config.pool_url);
+    info!("plot_folders: {:?}", miner_config.folder_paths());
 
-    let mut data = String::new();
+    // before any thread is started, so they all leave the signals to `wait_for_exit`
+    try!(shutdown::block_signals());
+    let running = try!(run(miner_config));
+    std::process::exit(wait_for_exit(running, &args, logging));
+}
 
-    config_file.read_to_string(&mut data).unwrap();
-    let miner_config = json::decode::<config::MinerConfiguration>(&data).unwrap();
-    println!("found config!");
-    println!("pool_url: {:?}", miner_config.pool_url);
-    println!("plot_folders: {:?}", miner_config.plot_folders);
+/// What is needed to reconfigure a running miner and to shut it down.
+struct Running {
+    /// the configuration mining started with, or the one last reloaded
+    config: MinerConfiguration,
+    pool: Pool,
+    pool_thread: Option<thread::JoinHandle<()>>,
+    rescan: Sender<RescanSettings>,
+    read_errors: ReadErrors,
+    shutdown_timeout: Duration,
+}
 
-    let plot_folders = plots::get_plots(miner_config.plot_folders.unwrap());
+enum Event {
+    Signal(libc::c_int),
+    /// SIGHUP or a change to the config file
+    Reload,
+    PoolDied(String),
+}
 
-    let pool = Pool::from_url(Url::parse(&miner_config.pool_url.unwrap()).unwrap());
+/// Waits for SIGINT, SIGTERM or the pool thread to die and returns the exit status, reloading
+/// the configuration on SIGHUP and, with `watch_config`, when the config file changes.
+///
+/// On SIGINT or SIGTERM the pool is stopped and the submissions in flight get
+/// `shutdown_timeout` to finish. A second one exits right away.
+fn wait_for_exit(mut running: Running, args: &cli::Args, logging: &Logging) -> i32 {
+    let (events, events_recv) = channel();
 
-    for folder in &plot_folders.folders {
-        let plots = folder.plots.clone();
-        let pool = pool.clone();
+    let signal_events = events.clone();
+    thread::spawn(move || {
+        let mut received = false;
+        loop {
+            match shutdown::wait_for_signal() {
+                Ok(libc::SIGHUP) => {
+                    let _ = signal_events.send(Event::Reload);
+                }
+                Ok(signal) if received => {
+                    warn!("{} received again, exiting without waiting",
+                          shutdown::signal_name(signal));
+                    std::process::exit(1);
+                }
+                Ok(signal) => {
+                    received = true;
+                    let _ = signal_events.send(Event::Signal(signal));
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            }
+        }
+    });
 
-        let (signature_sender, signature_recv) = channel();
-        pool.add_subscriber(signature_sender).unwrap();
+    if running.config.watch_config == Some(true) {
+        match args.config_path() {
+            Some(path) => {
+                let watch_events = events.clone();
+                reload::watch(path.to_path_buf(), Duration::from_secs(2), move || {
+                    let _ = watch_events.send(Event::Reload);
+                });
+            }
+            None => warn!("watch_config: there is no config file to watch"),
+        }
+    }
+
+    let pool_thread = running.pool_thread.take().unwrap();
+    thread::spawn(move || {
+        let reason = match pool_thread.join() {
+            Ok(()) => String::from("finished"),
+            Err(panic) => supervisor::panic_message(&panic),
+        };
+        let _ = events.send(Event::PoolDied(reason));
+    });
+
+    let status = loop {
+        match events_recv.recv() {
+            Ok(Event::Reload) => {
+                let notifier = Notifier::from_env().unwrap_or(None);
+                if let Some(ref notifier) = notifier {
+                    let _ = notifier.notify("RELOADING=1");
+                }
+                if let Err(e) = reload(&mut running, args, logging) {
+                    error!("keeping the running configuration, the new one was rejected: {}",
+                           e);
+                }
+                if let Some(ref notifier) = notifier {
+                    let _ = notifier.notify("READY=1");
+                }
+            }
+            Ok(Event::Signal(signal)) => {
+                info!("{} received, shutting down", shutdown::signal_name(signal));
+                if let Ok(Some(notifier)) = Notifier::from_env() {
+                    let _ = notifier.notify("STOPPING=1");
+                }
+                let finished = running.pool.stop(running.shutdown_timeout);
+                for line in running.read_errors.report() {
+                    warn!("{}", line);
+                }
+                break if finished { 0 } else { 1 };
+            }
+            // the miner threads restart themselves, without the pool there is nothing left to
+            // mine
+            Ok(Event::PoolDied(reason)) => {
+                error!("pool thread died: {}, exiting", reason);
+                break 1;
+            }
+            Err(_) => break 1,
+        }
+    };
+    let _ = io::stdout().flush();
+    status
+}
 
-        thread::spawn::<_, i32>(move || {
-            miner::mine(pool, signature_recv, plots);
-            0
-        });
+/// Reads the configuration again and hands the changes to the pool and the rescan thread, the
+/// miner threads mine with them from the next block on. A configuration with problems is
+/// rejected as a whole.
+fn reload(running: &mut Running, args: &cli::Args, logging: &Logging) -> Result<(), String> {
+    let new_config = try!(args.load_config(&["pool_url", "plot_folders"]));
+    let changes = running.config.changes(&new_config);
+    if changes.is_empty() {
+        info!("configuration reloaded, nothing changed");
+        return Ok(());
+    }
+    let pool_settings = try!(pool_settings(&new_config));
+    let rescan_settings = try!(rescan_settings(&new_config));
+    // the only part that can still fail, a log file that can't be opened
+    try!(logging.apply(try!(new_config.log_settings())));
+    running.pool.reconfigure(pool_settings);
+    let _ = running.rescan.send(rescan_settings);
+    running.shutdown_timeout = shutdown_timeout(&new_config);
+    info!("configuration reloaded, {} changed", changes.join(", "));
+    let restart: Vec<&str> = changes.into_iter()
+        .filter(|name| reload::RESTART_FIELDS.contains(name))
+        .collect();
+    if !restart.is_empty() {
+        warn!("restart the miner for {} to take effect", restart.join(", "));
     }
-    pool.start();
+    running.config = new_config;
+    Ok(())
+}
+
+fn pool_settings(miner_config: &MinerConfiguration) -> Result<PoolSettings, String> {
+    let url = match miner_config.pool_url {
+        Some(ref pool_url) => try!(Url::parse(pool_url).map_err(|e| format!("pool_url: {}", e))),
+        None => return Err(String::from("no pool_url configured")),
+    };
+    Ok(PoolSettings {
+        url: url,
+        max_deadline: miner_config.max_deadline.map(|max_deadline| max_deadline as u64),
+    })
+}
+
+fn rescan_settings(miner_config: &MinerConfiguration) -> Result<RescanSettings, String> {
+    let folder_paths = match miner_config.folder_paths() {
+        Some(folder_paths) => folder_paths,
+        None => return Err(String::from("no plot_folders configured")),
+    };
+    Ok(RescanSettings {
+        folder_paths: folder_paths,
+        options: try!(miner_config.scan_options()),
+        device_threads: miner_config.device_threads.clone().unwrap_or_default(),
+        interval: match miner_config.rescan_interval.unwrap_or(60) {
+            0 => None,
+            interval => Some(Duration::from_secs(interval as u64)),
+        },
+    })
+}
+
+fn shutdown_timeout(miner_config: &MinerConfiguration) -> Duration {
+    Duration::from_secs(miner_config.shutdown_timeout.unwrap_or(10) as u64)
+}
+
+/// Starts mining, returns what is needed to reconfigure and stop it.
+fn run(miner_config: MinerConfiguration) -> Result<Running, String> {
+    let settings = try!(rescan_settings(&miner_config));
+    let read_errors = miner_config.read_errors();
+    let plot_folders = try!(plots::get_usable_plots(settings.folder_paths.clone(),
+                                                    &settings.options));
 
-    loop {
-        thread::sleep(Duration::from_secs(10));
+    let mut pool = Pool::with_settings(try!(pool_settings(&miner_config)));
+    if let Some(ref capture_file) = miner_config.capture_file {
+        pool = pool.with_capture(try!(Capture::open(Path::new(capture_file))));
     }
+    let notifier = try!(Notifier::from_env());
+    if notifier.is_some() {
+        pool = pool.with_progress(Progress::new());
+    }
+
+    let (rescan, signature_senders) = Rescan::spawn(settings.folder_paths,
+                                                    settings.options,
+                                                    settings.device_threads,
+                                                    &plot_folders,
+                                                    pool.clone(),
+                                                    read_errors.clone());
+    for signature_sender in signature_senders {
+        pool.add_subscriber(signature_sender).unwrap();
+    }
+    let pool_thread = pool.start().unwrap();
+    if let (Some(notifier), Some(progress)) = (notifier, pool.progress()) {
+        notify::start(notifier, progress.clone());
+    }
+    let rescan = rescan::start(rescan, settings.interval, pool.clone());
+    Ok(Running {
+        shutdown_timeout: shutdown_timeout(&miner_config),
+        config: miner_config,
+        pool: pool,
+        pool_thread: Some(pool_thread),
+        rescan: rescan,
+        read_errors: read_errors,
+    })
 }
 
-fn usage() {
-    println!("rust-miner [-config={{ path_to_config }}");
+#[cfg(test)]
+mod tests {
+    use config::{FolderConfig, MinerConfiguration};
+    use mock_pool::{MockPool, Submission, mining_info_json};
+    use std::time::Duration;
+
+    const ACCOUNT_ID: u64 = 15250033402865692318;
+    const BASE_TARGET: u64 = 18325193796;
+
+    #[test]
+    fn mines_bundled_plots_against_mock_pool() {
+        // the best nonce for the first signature is 6 with a deadline of 37542209s, one
+        // second over the target deadline, so only the second block gets a submission
+        let mock = MockPool::start(vec![
+            mining_info_json("2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1",
+                             BASE_TARGET,
+                             480000,
+                             37542208),
+            mining_info_json("d6a8bd1f9b1eaf3f6f47bb4cd79e8b3a1e64c1a5f27f2bd9c4b6b8a9e0e5f6c2",
+                             BASE_TARGET,
+                             480001,
+                             4294967295),
+        ]);
+        let submissions = mock.subscribe();
+
+        let mut running = super::run(MinerConfiguration {
+                pool_url: Some(mock.url().to_string()),
+                plot_folders: Some(vec![FolderConfig::new("plots")]),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let submission = submissions.recv_timeout(Duration::from_secs(30))
+            .expect("miner never submitted a nonce");
+        assert_eq!(submission,
+                   Submission {
+                       account_id: ACCOUNT_ID,
+                       nonce: 5,
+                       block: 1,
+                   });
+
+        // stopping waits for the submissions in flight, a duplicate would have arrived by now
+        assert!(running.pool.stop(Duration::from_secs(10)));
+        running.pool_thread.take().unwrap().join().unwrap();
+        drop(running);
+        assert!(submissions.try_recv().is_err());
+    }
 }
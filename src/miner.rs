@@ -1,16 +1,21 @@
-extern crate memmap;
-
 use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian, BigEndian};
 use constants::*;
-use plots::Plot;
+use devices::MinerGroups;
+use plots::{Plot, PlotHealth};
 use pool;
-use rustc_serialize::hex::FromHex;
-use std::fs::{File, metadata};
+use plots;
+use read_errors::ReadErrors;
+use reader::{PlotFiles, ReadBackend, ReadError, ScoopSource};
+use rustc_serialize::hex::{FromHex, FromHexError, ToHex};
 use std::io::{Cursor, Write};
-use std::sync::mpsc::{Receiver, TryRecvError};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::thread;
 use std::time::{Duration, Instant};
 use sph_shabal;
-use self::memmap::{Mmap, Protection};
+use supervisor::{self, Backoff};
+use uring::Completion;
 
 #[derive(Copy)]
 pub struct MinerWork {
@@ -30,23 +35,10 @@ impl Clone for MinerWork {
 impl MinerWork {
     pub fn from_mining_info(mining_info: pool::MiningInfo) -> Result<MinerWork, pool::Error> {
         let sig = try!(mining_info.generation_signature.from_hex());
-
-        let mut height_vec = vec![];
-        try!(height_vec.write_u64::<BigEndian>(mining_info.height));
-        let height = &height_vec[..];
-        let mut scoop_prefix: [u8; 40] = [0; 40];
-
-        try!((&mut scoop_prefix[0..32]).write(&sig[..]));
-        try!((&mut scoop_prefix[32..40]).write(height));
-        // println!("scoop prefix:    {:?}", scoop_prefix.to_hex());
-
-        let scoop_prefix_shabal = sph_shabal::shabal256(&scoop_prefix);
-        // println!("shabaled prefix: {:?}", scoop_prefix_shabal.to_hex());
-
-        let scoop_check_arr = &scoop_prefix_shabal[30..];
-        let mut cur = Cursor::new(scoop_check_arr);
-        let scoop_num: u16 = cur.read_u16::<BigEndian>().unwrap() % 4096;
-        // println!("scoop num:       {:?}", scoop_num);
+        if sig.len() != 32 {
+            return Err(pool::Error::FromHex(FromHexError::InvalidHexLength));
+        }
+        let scoop_num = scoop_number(&sig, mining_info.height);
 
         let mut hasher: [u8; 32 + HASH_SIZE * 2] = [0; 32 + HASH_SIZE * 2];
 
@@ -63,110 +55,571 @@ impl MinerWork {
     }
 }
 
-pub fn mine(pool: pool::Pool, signature_recv: Receiver<MinerWork>, plots: Vec<Plot>) {
+/// Picks the scoop every nonce has to provide for a block: the last two bytes of
+/// `shabal256(generation_signature || height)` modulo the number of scoops.
+pub fn scoop_number(generation_signature: &[u8], height: u64) -> u16 {
+    let mut scoop_prefix: [u8; 40] = [0; 40];
+    scoop_prefix[0..32].copy_from_slice(generation_signature);
+    (&mut scoop_prefix[32..40]).write_u64::<BigEndian>(height).unwrap();
+    let scoop_prefix_shabal = sph_shabal::shabal256(&scoop_prefix);
+    trace!("scoop prefix {}, shabaled {}",
+           scoop_prefix.to_hex(),
+           scoop_prefix_shabal.to_hex());
+
+    let mut cur = Cursor::new(&scoop_prefix_shabal[30..]);
+    cur.read_u16::<BigEndian>().unwrap() % HASH_CAP as u16
+}
+
+/// The raw hash value of one scoop, the deadline in seconds is this divided by the base target.
+pub fn scoop_hash(generation_signature: &[u8], scoop: &[u8]) -> u64 {
+    let mut hasher: [u8; 32 + HASH_SIZE * 2] = [0; 32 + HASH_SIZE * 2];
+    hasher[0..32].copy_from_slice(generation_signature);
+    hasher[32..].copy_from_slice(scoop);
+    hash_value(&hasher)
+}
+
+/// Plots the nonce in memory and returns the raw hash value of the scoop picked for the block.
+pub fn nonce_hash(generation_signature: &[u8], height: u64, account_id: u64, nonce: u64) -> u64 {
+    let scoop_num = scoop_number(generation_signature, height) as usize;
+    let nonce_data = plots::plot_nonce(account_id, nonce);
+    scoop_hash(generation_signature,
+               &nonce_data[scoop_num * HASH_SIZE * 2..(scoop_num + 1) * HASH_SIZE * 2])
+}
+
+/// Deadline in seconds of a nonce, computed without touching any plot file.
+pub fn nonce_deadline(generation_signature: &[u8],
+                      height: u64,
+                      base_target: u64,
+                      account_id: u64,
+                      nonce: u64)
+                      -> u64 {
+    nonce_hash(generation_signature, height, account_id, nonce) / base_target
+}
+
+fn hash_value(hasher: &[u8]) -> u64 {
+    let outhash = sph_shabal::shabal256(hasher);
+    let mut hash_cur = Cursor::new(&outhash[0..8]);
+    hash_cur.read_u64::<LittleEndian>().unwrap()
+}
+
+/// Receives everything a miner thread finds.
+pub trait NonceSubmitter {
+    fn submit_nonce(&self, nonce: u64, account_id: u64) -> Result<String, pool::Error>;
+
+    /// Called once per block with the best nonce of every account, even when the round was
+    /// cut short by the next block.
+    fn round_finished(&self, _miner_work: &MinerWork, _result: &RoundResult) {}
+
+    /// Called when a round starts and then at least every 500ms while it is being read.
+    fn progress(&self, _miner_work: &MinerWork) {}
+
+    /// Called when the thread panics in the middle of a round, instead of `round_finished`.
+    fn round_crashed(&self, _miner_work: &MinerWork) {}
+}
+
+impl NonceSubmitter for pool::Pool {
+    fn submit_nonce(&self, nonce: u64, account_id: u64) -> Result<String, pool::Error> {
+        self.submit_hash(nonce, account_id)
+    }
+
+    fn round_finished(&self, miner_work: &MinerWork, result: &RoundResult) {
+        if let Some(progress) = self.progress() {
+            let deadline = result.best.iter().map(|best| best.hash / miner_work.base_target).min();
+            progress.miner_idle(&current_thread_name(), miner_work.height, deadline);
+        }
+    }
+
+    fn progress(&self, _miner_work: &MinerWork) {
+        if let Some(progress) = self.progress() {
+            progress.miner_busy(&current_thread_name());
+        }
+    }
+
+    fn round_crashed(&self, _miner_work: &MinerWork) {
+        if let Some(progress) = self.progress() {
+            progress.miner_crashed(&current_thread_name());
+        }
+    }
+}
+
+fn current_thread_name() -> String {
+    thread::current().name().unwrap_or("miner").to_owned()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccountBest {
+    pub account_id: u64,
+    pub nonce: u64,
+    pub hash: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoundResult {
+    pub nonces_read: u64,
+    pub interrupted: bool,
+    pub best: Vec<AccountBest>,
+}
+
+impl RoundResult {
+    fn record(&mut self, candidate: AccountBest) {
+        match self.best.iter().position(|b| b.account_id == candidate.account_id) {
+            Some(i) if self.best[i].hash <= candidate.hash => {}
+            Some(i) => self.best[i] = candidate,
+            None => self.best.push(candidate),
+        }
+    }
+}
+
+/// The plots of one miner thread, replaced between rounds when the plot folders are rescanned.
+pub type SharedPlots = Arc<Mutex<Vec<Plot>>>;
+
+/// Starts one miner thread per group of plots and returns the senders to feed them work.
+pub fn spawn<S>(groups: &MinerGroups, submitter: S, errors: &ReadErrors) -> Vec<Sender<MinerWork>>
+    where S: NonceSubmitter + Clone + Send + 'static
+{
+    groups.iter()
+        .map(|(key, plots)| {
+            spawn_miner(thread_name(key), plots.clone(), submitter.clone(), errors.clone()).1
+        })
+        .collect()
+}
+
+/// The name of the miner thread for a group of plots, e.g. `miner sda/0`.
+pub fn thread_name(key: &(String, u32)) -> String {
+    format!("miner {}/{}", key.0, key.1)
+}
+
+/// Starts a miner thread for `plots`, they can be swapped through the returned handle.
+///
+/// The thread is restarted when it panics, it picks up the plots, the work channel and the block
+/// where the crashed one left them.
+pub fn spawn_miner<S>(name: String,
+                      plots: Vec<Plot>,
+                      submitter: S,
+                      errors: ReadErrors)
+                      -> (SharedPlots, Sender<MinerWork>)
+    where S: NonceSubmitter + Clone + Send + 'static
+{
+    let plots = Arc::new(Mutex::new(plots));
+    let thread_plots = plots.clone();
+    let (signature_sender, signature_recv) = channel();
+    // a panicking thread poisons the mutex, its successor takes the receiver anyway
+    let signature_recv = Arc::new(Mutex::new(signature_recv));
+    let crashed_work = Arc::new(Mutex::new(None));
+
+    let backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    let thread_name = name.clone();
+    supervisor::supervise(name, backoff, move || {
+        let submitter = submitter.clone();
+        let signature_recv = signature_recv.clone();
+        let plots = thread_plots.clone();
+        let errors = errors.clone();
+        let crashed_work = crashed_work.clone();
+        thread::Builder::new()
+            .name(thread_name.clone())
+            .spawn(move || {
+                let signature_recv = signature_recv.lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                mine(submitter, &PlotFiles, &signature_recv, plots, errors, &crashed_work);
+            })
+            .unwrap()
+    });
+    (plots, signature_sender)
+}
+
+/// Mines every block `signature_recv` hands out, starting with the one a crashed predecessor
+/// left in `crashed_work`.
+pub fn mine<S: NonceSubmitter>(submitter: S,
+                               source: &ScoopSource,
+                               signature_recv: &Receiver<MinerWork>,
+                               shared_plots: SharedPlots,
+                               errors: ReadErrors,
+                               crashed_work: &Mutex<Option<MinerWork>>) {
 
-    let mut next_work: Option<MinerWork> = None;
+    let mut next_work: Option<MinerWork> =
+        crashed_work.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
 
     loop {
-        // println!("start mine loop");
-        let miner_work = match next_work {
+        let miner_work = match next_work.take() {
             Some(t) => t,
-            None => signature_recv.recv().unwrap(),
+            None => {
+                match signature_recv.recv() {
+                    Ok(t) => t,
+                    Err(_) => return, // nobody is going to send work anymore
+                }
+            }
         };
-
-        let mut hasher = miner_work.hasher;
-        let scoop_num = miner_work.scoop_num;
+        // a rescan only takes effect once the round in progress is over
+        let plots = shared_plots.lock().unwrap().clone();
         let start_time = Instant::now();
+        debug!("mining height {}, scoop {} of {} plots",
+               miner_work.height,
+               miner_work.scoop_num,
+               plots.len());
 
-        let deadline = miner_work.target_deadline * miner_work.base_target;
+        submitter.progress(&miner_work);
+        let mut round = Round::new(&submitter,
+                                   source,
+                                   signature_recv,
+                                   miner_work,
+                                   &errors,
+                                   &shared_plots,
+                                   crashed_work);
 
-        let mut nonce_count = 0;
-        let mut last_submit: Option<u64> = None;
-        let mut best_nonce: Option<u64> = None;
-        let mut best_account_id: Option<u64> = None;
-        let mut best_hash: Option<u64> = None;
+        let (uring_plots, sync_plots): (Vec<Plot>, Vec<Plot>) =
+            plots.into_iter().partition(|plot| plot.read_backend == ReadBackend::Uring);
+        for plot in &sync_plots {
+            if round.result.interrupted {
+                break;
+            }
+            round.mine_plot(plot);
+        }
+        if !uring_plots.is_empty() && !round.result.interrupted {
+            round.mine_uring(&uring_plots);
+        }
 
-        'miner_run: for plot in &plots {
-            // println!("read file: {:?}", &plot.path);
-            let plot_start_time = Instant::now();
-            let mut last_check_time = plot_start_time;
-            let mut nonce = plot.start_nonce;
+        if !round.result.interrupted {
+            // small plot sets finish before the first periodic check, submit what we found
+            round.submit_best();
+        }
+        if round.had_errors {
+            for line in errors.report() {
+                warn!("{}", line);
+            }
+        }
+        mark_failed(&shared_plots, &round.failed);
+        submitter.round_finished(&miner_work, &round.result);
+        next_work = round.next_work;
+        info!("finished reading in {:?}", Instant::now() - start_time);
+    }
+}
 
-            let scoop_offset = plot.stagger_size as i64 * scoop_num as i64 * HASH_SIZE as i64 * 2;
+/// What a miner thread keeps track of while it reads the scoops of one block.
+struct Round<'a, S: NonceSubmitter + 'a> {
+    submitter: &'a S,
+    source: &'a ScoopSource,
+    signature_recv: &'a Receiver<MinerWork>,
+    miner_work: MinerWork,
+    hasher: [u8; 32 + HASH_SIZE * 2],
+    /// the best nonce of any account so far
+    best: Option<AccountBest>,
+    last_submit: Option<u64>,
+    last_check_time: Instant,
+    /// work for the next block that cut this round short
+    next_work: Option<MinerWork>,
+    /// plots that couldn't be read and why
+    failed: Vec<(PathBuf, String)>,
+    errors: &'a ReadErrors,
+    /// whether a read had to be retried or skipped this round
+    had_errors: bool,
+    result: RoundResult,
+    /// the plot whose scoops are being read or hashed, blamed if the thread crashes
+    reading: Option<PathBuf>,
+    shared_plots: &'a SharedPlots,
+    /// where the block goes for the thread that takes over after a crash
+    crashed_work: &'a Mutex<Option<MinerWork>>,
+}
 
-            let stagger_count = plot.nonce_count / plot.stagger_size;
+impl<'a, S: NonceSubmitter> Round<'a, S> {
+    fn new(submitter: &'a S,
+           source: &'a ScoopSource,
+           signature_recv: &'a Receiver<MinerWork>,
+           miner_work: MinerWork,
+           errors: &'a ReadErrors,
+           shared_plots: &'a SharedPlots,
+           crashed_work: &'a Mutex<Option<MinerWork>>)
+           -> Round<'a, S> {
+        Round {
+            submitter: submitter,
+            source: source,
+            signature_recv: signature_recv,
+            miner_work: miner_work,
+            hasher: miner_work.hasher,
+            best: None,
+            last_submit: None,
+            last_check_time: Instant::now(),
+            next_work: None,
+            failed: Vec::new(),
+            errors: errors,
+            had_errors: false,
+            result: RoundResult {
+                nonces_read: 0,
+                interrupted: false,
+                best: Vec::new(),
+            },
+            reading: None,
+            shared_plots: shared_plots,
+            crashed_work: crashed_work,
+        }
+    }
 
-            let map_len = plot.stagger_size as usize * HASH_SIZE * 2;
+    /// Reads a plot one stagger at a time.
+    fn mine_plot(&mut self, plot: &Plot) {
+        // degraded plots are mined up to where they are intact, unusable ones not at all
+        let usable_nonces = plot.usable_nonces();
+        if usable_nonces == 0 {
+            return;
+        }
+        let stagger_count = usable_nonces / plot.stagger_size;
+        let scoop_num = self.miner_work.scoop_num;
 
-            let file = File::open(&plot.path).unwrap();
-            let file_len = metadata(&plot.path).unwrap().len();
+        // the plot may have been deleted since the last rescan
+        let mut reader = match self.source.open(plot) {
+            Ok(reader) => reader,
+            Err(e) => {
+                warn!("skipping {:?}: {}", &plot.path, e);
+                return;
+            }
+        };
 
-            for stagger in 0..stagger_count {
-                let stagger_offset = stagger as i64 * HASH_CAP as i64 * HASH_SIZE as i64 * 2 *
-                                     plot.stagger_size as i64 +
-                                     scoop_offset;
-                if map_len as u64 + stagger_offset as u64 > file_len {
-                    println!("past end of file {:?}", &plot.path);
-                    break;
-                }
-                let mmap_stagger = Mmap::open_with_offset(&file,
-                                                          Protection::Read,
-                                                          stagger_offset,
-                                                          map_len)
-                    .unwrap();
-                let buf = unsafe { mmap_stagger.as_slice() };
-
-                for nonce_in_stagger in 0..plot.stagger_size {
-                    (& mut hasher[32..(32 + HASH_SIZE * 2)])
-                        .write(&buf[nonce_in_stagger as usize * HASH_SIZE * 2..(nonce_in_stagger as usize + 1) *
-                                                                     HASH_SIZE *
-                                                                     2])
-                        .unwrap();
-                    let outhash = sph_shabal::shabal256(&hasher);
-                    let mut hash_cur = Cursor::new(&outhash[0..8]);
-                    let test_num = hash_cur.read_u64::<LittleEndian>().unwrap();
-                    // println!("hash: {} nonce: {}", test_num, nonce);
-                    match best_hash {
-                        Some(existing_hash) if existing_hash <= test_num => {}
-                        _ => {
-                            best_hash = Some(test_num);
-                            best_nonce = Some(nonce);
-                            best_account_id = Some(plot.account_id);
-                        }
+        for stagger in 0..stagger_count {
+            let first_nonce = plot.start_nonce + stagger * plot.stagger_size;
+            if plot.is_excluded(first_nonce, first_nonce + plot.stagger_size) {
+                continue;
+            }
+            if stagger + 1 < stagger_count {
+                reader.prefetch(stagger + 1, scoop_num);
+            }
+            self.reading = Some(plot.path.clone());
+            let mut tries = 0;
+            loop {
+                let error = match reader.scoops(stagger, scoop_num) {
+                    Ok(buf) => {
+                        self.hash_stagger(plot, first_nonce, buf);
+                        break;
                     }
-
-                    // println!("{:?}", best_hash);
-                    nonce_count += 1;
-                    nonce += 1;
+                    Err(ReadError::PastEnd) => {
+                        self.failed.push((plot.path.clone(), format!("{}", ReadError::PastEnd)));
+                        self.reading = None;
+                        return;
+                    }
+                    Err(ReadError::Failed(error)) => error,
+                };
+                if tries < self.errors.retries {
+                    tries += 1;
+                    self.errors.record_retry(plot);
+                    self.had_errors = true;
+                    continue;
+                }
+                if self.skip_stagger(plot, stagger, &error) {
+                    self.reading = None;
+                    return;
                 }
+                break;
+            }
+            self.reading = None;
+            if self.check() {
+                return;
+            }
+        }
+    }
 
-                let time_since_check = Instant::now() - last_check_time;
-                if time_since_check > Duration::from_millis(500) {
-                    last_check_time = Instant::now();
-                    if has_new_signature(&signature_recv, &mut next_work) {
-                        println!("read {} nonces in {:?}", nonce_count, time_since_check);
-                        break 'miner_run;
+    /// Reads the staggers of all plots at once through io_uring, in whatever order the reads
+    /// complete.
+    fn mine_uring(&mut self, plots: &[Plot]) {
+        let mut reads = match self.source.stagger_reads(plots,
+                                                        self.miner_work.scoop_num,
+                                                        self.errors.retries) {
+            Ok(reads) => reads,
+            Err(e) => {
+                warn!("io_uring: {}, reading {} plots one stagger at a time",
+                      e,
+                      plots.len());
+                for plot in plots {
+                    if self.result.interrupted {
+                        break;
                     }
-
-                    if best_hash.unwrap() < deadline && best_nonce != last_submit {
-                        println!("found nonce {} Duration: {:?}",
-                                 best_nonce.unwrap(),
-                                 Duration::from_secs(best_hash.unwrap() / miner_work.base_target));
-                        for i in 0..3 {
-                            match pool.submit_hash(best_nonce.unwrap(), best_account_id.unwrap()) {
-                                Ok(t) => {
-                                    println!("try {} pool response: {}", i, t);
-                                    last_submit = best_nonce;
-                                    break;
-                                }
-                                Err(e) => println!("try {} pool error: {:?}", i, e),
-                            };
-                        }
+                    self.mine_plot(plot);
+                }
+                return;
+            }
+        };
+        loop {
+            match reads.next() {
+                Some(Completion::Scoops { plot, stagger, scoops, retries }) => {
+                    let plot = &plots[plot];
+                    self.record_retries(plot, retries);
+                    self.reading = Some(plot.path.clone());
+                    self.hash_stagger(plot, plot.start_nonce + stagger * plot.stagger_size, scoops);
+                    self.reading = None;
+                }
+                Some(Completion::StaggerFailed { plot: index, stagger, error, retries }) => {
+                    self.record_retries(&plots[index], retries);
+                    if self.skip_stagger(&plots[index], stagger, &error) {
+                        reads.drop_plot(index);
                     }
                 }
+                Some(Completion::PlotFailed { plot, error }) => {
+                    self.failed.push((plots[plot].path.clone(), error));
+                }
+                Some(Completion::RingFailed(error)) => {
+                    warn!("io_uring: {}", error);
+                    return;
+                }
+                None => return,
+            }
+            // dropping the reads waits for the ones still in flight
+            if self.check() {
+                return;
+            }
+        }
+    }
+
+    fn record_retries(&mut self, plot: &Plot, retries: u32) {
+        for _ in 0..retries {
+            self.errors.record_retry(plot);
+            self.had_errors = true;
+        }
+    }
+
+    /// Leaves out a stagger every try to read failed, returns true when that got the plot
+    /// quarantined.
+    fn skip_stagger(&mut self, plot: &Plot, stagger: u64, error: &str) -> bool {
+        warn!("skipping stagger {} of {:?} after {} tries: {}",
+              stagger,
+              &plot.path,
+              self.errors.retries + 1,
+              error);
+        self.had_errors = true;
+        if !self.errors.record_skipped(plot) {
+            return false;
+        }
+        let skipped = self.errors.plot_count(&plot.path).skipped_staggers;
+        self.failed.push((plot.path.clone(),
+                          format!("quarantined after {} skipped staggers, last error: {}",
+                                  skipped,
+                                  error)));
+        true
+    }
+
+    fn hash_stagger(&mut self, plot: &Plot, first_nonce: u64, scoops: &[u8]) {
+        // rate limited folders are held back here, whichever backend read the scoops
+        if let Some(ref throttle) = plot.throttle {
+            throttle.read(scoops.len());
+        }
+        let mut stagger_best: Option<AccountBest> = None;
+        for nonce_in_stagger in 0..plot.stagger_size as usize {
+            (&mut self.hasher[32..(32 + HASH_SIZE * 2)])
+                .write(&scoops[nonce_in_stagger * HASH_SIZE * 2..(nonce_in_stagger + 1) *
+                                                                 HASH_SIZE *
+                                                                 2])
+                .unwrap();
+            let test_num = hash_value(&self.hasher);
+            // `nonce=trace` logs every nonce, checking the level costs next to nothing otherwise
+            trace!(target: "nonce",
+                   "account {} nonce {} hash {} deadline {}",
+                   plot.account_id,
+                   first_nonce + nonce_in_stagger as u64,
+                   test_num,
+                   test_num / self.miner_work.base_target);
+            match stagger_best {
+                Some(existing) if existing.hash <= test_num => {}
+                _ => {
+                    stagger_best = Some(AccountBest {
+                        account_id: plot.account_id,
+                        nonce: first_nonce + nonce_in_stagger as u64,
+                        hash: test_num,
+                    })
+                }
             }
         }
-        println!("finished reading in {:?}", Instant::now() - start_time);
+        self.result.nonces_read += plot.stagger_size;
+
+        if let Some(stagger_best) = stagger_best {
+            match self.best {
+                Some(existing) if existing.hash <= stagger_best.hash => {}
+                _ => self.best = Some(stagger_best),
+            }
+            self.result.record(stagger_best);
+        }
+    }
+
+    /// Looks for new work every 500ms and submits the best nonce so far while there is none,
+    /// returns true when the round has to stop.
+    fn check(&mut self) -> bool {
+        let time_since_check = Instant::now() - self.last_check_time;
+        if time_since_check <= Duration::from_millis(500) {
+            return false;
+        }
+        self.last_check_time = Instant::now();
+        self.submitter.progress(&self.miner_work);
+        if has_new_signature(self.signature_recv, &mut self.next_work) {
+            info!("read {} nonces in {:?}",
+                  self.result.nonces_read,
+                  time_since_check);
+            self.result.interrupted = true;
+            return true;
+        }
+        self.submit_best();
+        false
+    }
+
+    fn submit_best(&mut self) {
+        submit_best(self.submitter,
+                    &self.miner_work,
+                    self.best,
+                    &mut self.last_submit);
+    }
+}
+
+impl<'a, S: NonceSubmitter + 'a> Drop for Round<'a, S> {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        // a crashed thread would stay busy forever, its successor reports for itself
+        self.submitter.round_crashed(&self.miner_work);
+        // the same plot would crash the successor again
+        if let Some(path) = self.reading.take() {
+            self.errors.quarantine(&path);
+            let error = String::from("quarantined, the miner thread crashed reading it");
+            mark_failed(self.shared_plots, &[(path, error)]);
+        }
+        // a newer block that came in during the round replaces this one
+        let work = self.next_work.unwrap_or(self.miner_work);
+        *self.crashed_work.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(work);
+    }
+}
+
+/// Stops mining plots that failed to read until a rescan finds them intact again.
+fn mark_failed(shared_plots: &SharedPlots, failed: &[(PathBuf, String)]) {
+    if failed.is_empty() {
+        return;
+    }
+    let mut plots = shared_plots.lock().unwrap();
+    for &(ref path, ref error) in failed {
+        error!("plot {:?} failed: {}, mining the other plots without it", path, error);
+        // a rescan may have replaced the plots during the round
+        for plot in plots.iter_mut().filter(|plot| plot.path == *path) {
+            plot.health = PlotHealth::Unusable(format!("read failed: {}", error));
+        }
+    }
+}
+
+fn submit_best<S: NonceSubmitter>(submitter: &S,
+                                  miner_work: &MinerWork,
+                                  best: Option<AccountBest>,
+                                  last_submit: &mut Option<u64>) {
+    let deadline = miner_work.target_deadline.saturating_mul(miner_work.base_target);
+    let best = match best {
+        Some(best) => best,
+        None => return,
+    };
+    if best.hash >= deadline || Some(best.nonce) == *last_submit {
+        return;
+    }
+    info!("found nonce {} Duration: {:?}",
+          best.nonce,
+          Duration::from_secs(best.hash / miner_work.base_target));
+    for i in 0..3 {
+        match submitter.submit_nonce(best.nonce, best.account_id) {
+            Ok(t) => {
+                info!("try {} pool response: {}", i, t);
+                *last_submit = Some(best.nonce);
+                break;
+            }
+            Err(e) => warn!("try {} pool error: {:?}", i, e),
+        };
     }
 }
 
@@ -180,6 +633,484 @@ fn has_new_signature(recv: &Receiver<MinerWork>, next_work: &mut Option<MinerWor
             *next_work = None;
             false
         }
-        Err(TryRecvError::Disconnected) => panic!("signature sender disconnected"),
+        // nobody is going to send work anymore, the thread stops after this round
+        Err(TryRecvError::Disconnected) => {
+            *next_work = None;
+            true
+        }
     };
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Url;
+    use notify::Progress;
+    use pool::{MiningInfo, Pool};
+    use read_errors::ErrorCount;
+    use reader;
+    use rustc_serialize::hex::FromHex;
+    use rustc_serialize::json;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Read, Seek, SeekFrom};
+    use std::path::Path;
+    use testing::{FailingReads, TempDir};
+    use uring;
+
+    const ACCOUNT_ID: u64 = 15250033402865692318;
+    const BASE_TARGET: u64 = 18325193796;
+
+    struct Vector {
+        generation_signature: &'static str,
+        height: u64,
+        base_target: u64,
+        scoop: u16,
+        account_id: u64,
+        nonce: u64,
+        deadline: u64,
+    }
+
+    // `nonce_deadline` plots every nonce itself, so a vector can be any account and nonce. Those
+    // of the account and nonces in plots/ are also checked against the scoops the reference
+    // plotter wrote. The generation signatures are made up: no published mainnet block, with the
+    // account that won it, its nonce and the deadline the network accepted, was at hand yet.
+    const VECTORS: &'static [Vector] = &[
+        Vector {
+            generation_signature: "2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1",
+            height: 480000,
+            base_target: BASE_TARGET,
+            scoop: 1614,
+            account_id: ACCOUNT_ID,
+            nonce: 6,
+            deadline: 37542209,
+        },
+        Vector {
+            generation_signature: "2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1",
+            height: 480000,
+            base_target: BASE_TARGET,
+            scoop: 1614,
+            account_id: ACCOUNT_ID,
+            nonce: 11,
+            deadline: 963537822,
+        },
+        Vector {
+            generation_signature: "d6a8bd1f9b1eaf3f6f47bb4cd79e8b3a1e64c1a5f27f2bd9c4b6b8a9e0e5f6c2",
+            height: 480001,
+            base_target: BASE_TARGET,
+            scoop: 3280,
+            account_id: ACCOUNT_ID,
+            nonce: 5,
+            deadline: 104716254,
+        },
+        Vector {
+            generation_signature: "d6a8bd1f9b1eaf3f6f47bb4cd79e8b3a1e64c1a5f27f2bd9c4b6b8a9e0e5f6c2",
+            height: 480001,
+            base_target: BASE_TARGET,
+            scoop: 3280,
+            account_id: ACCOUNT_ID,
+            nonce: 14,
+            deadline: 893675984,
+        },
+        Vector {
+            generation_signature: "0000000000000000000000000000000000000000000000000000000000000000",
+            height: 1,
+            base_target: BASE_TARGET,
+            scoop: 2570,
+            account_id: ACCOUNT_ID,
+            nonce: 2,
+            deadline: 10732782,
+        },
+        Vector {
+            generation_signature: "0000000000000000000000000000000000000000000000000000000000000000",
+            height: 1,
+            base_target: BASE_TARGET / 2,
+            scoop: 2570,
+            account_id: ACCOUNT_ID,
+            nonce: 2,
+            deadline: 21465565,
+        },
+    ];
+
+    fn mining_info(vector: &Vector) -> MiningInfo {
+        json::decode(&format!("{{\"generationSignature\":\"{}\",\"baseTarget\":{},\
+                               \"requestProcessingTime\":0,\"height\":{},\
+                               \"targetDeadline\":0}}",
+                              vector.generation_signature,
+                              vector.base_target,
+                              vector.height))
+            .unwrap()
+    }
+
+    /// The block of the first vector, the best nonce in plots/ is 6 and nothing gets submitted.
+    fn first_block() -> MinerWork {
+        MinerWork::from_mining_info(mining_info(&VECTORS[0])).unwrap()
+    }
+
+    /// The plots found in `folder`, which holds no subfolders.
+    fn folder_plots(folder: &str) -> Vec<Plot> {
+        plots::get_plots(vec![folder.to_owned()], &Default::default()).folders.remove(0).plots
+    }
+
+    #[test]
+    fn scoop_number_vectors() {
+        for vector in VECTORS {
+            let sig = vector.generation_signature.from_hex().unwrap();
+            assert_eq!(scoop_number(&sig, vector.height), vector.scoop);
+
+            let work = MinerWork::from_mining_info(mining_info(vector)).unwrap();
+            assert_eq!(work.scoop_num, vector.scoop);
+            assert_eq!(&work.hasher[0..32], &sig[..]);
+        }
+    }
+
+    #[test]
+    fn nonce_deadline_vectors() {
+        for vector in VECTORS {
+            let sig = vector.generation_signature.from_hex().unwrap();
+            assert_eq!(nonce_deadline(&sig,
+                                      vector.height,
+                                      vector.base_target,
+                                      vector.account_id,
+                                      vector.nonce),
+                       vector.deadline,
+                       "nonce {} of {}",
+                       vector.nonce,
+                       vector.generation_signature);
+        }
+    }
+
+    #[test]
+    fn vectors_match_the_reference_plots() {
+        // the bundled plots hold nonces 0 to 7 and 8 to 15 in staggers of 2
+        let in_plots = VECTORS.iter()
+            .filter(|vector| vector.account_id == ACCOUNT_ID && vector.nonce < 16);
+        for vector in in_plots {
+            let sig = vector.generation_signature.from_hex().unwrap();
+            let start = vector.nonce / 8 * 8;
+            let mut file = fs::File::open(format!("plots/{}_{}_8_2", vector.account_id, start))
+                .unwrap();
+            let (offset, _) = reader::scoop_range(2, (vector.nonce - start) / 2, vector.scoop);
+            let mut scoop = [0u8; HASH_SIZE * 2];
+            file.seek(SeekFrom::Start((offset + (vector.nonce % 2) as usize * scoop.len()) as u64))
+                .unwrap();
+            file.read_exact(&mut scoop).unwrap();
+
+            assert_eq!(scoop_hash(&sig, &scoop) / vector.base_target,
+                       vector.deadline,
+                       "nonce {} of {}",
+                       vector.nonce,
+                       vector.generation_signature);
+        }
+    }
+
+    #[test]
+    fn scoop_hash_reads_first_eight_bytes_little_endian() {
+        let sig = VECTORS[0].generation_signature.from_hex().unwrap();
+        let nonce_data = plots::plot_nonce(ACCOUNT_ID, 6);
+        let scoop = &nonce_data[1614 * 64..1615 * 64];
+        assert_eq!(scoop_hash(&sig, scoop), 687968267496907762);
+        assert_eq!(scoop_hash(&sig, scoop) / BASE_TARGET, 37542209);
+    }
+
+    #[test]
+    fn rejects_short_generation_signature() {
+        let info: MiningInfo = json::decode("{\"generationSignature\":\"00ff\",\
+                                              \"baseTarget\":1,\"requestProcessingTime\":0,\
+                                              \"height\":1,\"targetDeadline\":0}")
+            .unwrap();
+        assert!(MinerWork::from_mining_info(info).is_err());
+    }
+
+    #[derive(Clone)]
+    struct RoundRecorder {
+        rounds: Sender<RoundResult>,
+    }
+
+    impl NonceSubmitter for RoundRecorder {
+        fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+            Ok(String::new())
+        }
+
+        fn round_finished(&self, _miner_work: &MinerWork, result: &RoundResult) {
+            self.rounds.send(result.clone()).unwrap();
+        }
+    }
+
+    #[test]
+    fn every_read_backend_finds_the_same_nonce() {
+        let miner_work = first_block();
+        let bundled = folder_plots("plots");
+
+        let mut backends = vec![ReadBackend::Mmap];
+        if reader::supports_direct(&bundled[0].path) {
+            backends.push(ReadBackend::Direct);
+        } else {
+            println!("direct skipped, the file system of plots/ rejects O_DIRECT");
+        }
+        if uring::supported() {
+            backends.push(ReadBackend::Uring);
+        } else {
+            println!("io_uring skipped, io_uring is not available");
+        }
+        for backend in backends {
+            let mut plots = bundled.clone();
+            for plot in &mut plots {
+                plot.read_backend = backend;
+            }
+            let (rounds, rounds_recv) = channel();
+            let (_, signature_sender) =
+                spawn_miner(String::from("miner"),
+                            plots,
+                            RoundRecorder { rounds: rounds },
+                            ReadErrors::new(2, 10));
+            signature_sender.send(miner_work).unwrap();
+
+            let result = rounds_recv.recv().unwrap();
+            assert_eq!(result.nonces_read, 16, "{:?}", backend);
+            assert_eq!(result.best,
+                       vec![AccountBest {
+                                account_id: ACCOUNT_ID,
+                                nonce: 6,
+                                hash: 687968267496907762,
+                            }],
+                       "{:?}",
+                       backend);
+        }
+    }
+
+    #[derive(Clone)]
+    struct CrashOnce {
+        crashed: Arc<Mutex<bool>>,
+        recorder: RoundRecorder,
+    }
+
+    impl NonceSubmitter for CrashOnce {
+        fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+            Ok(String::new())
+        }
+
+        fn round_finished(&self, miner_work: &MinerWork, result: &RoundResult) {
+            let first = !::std::mem::replace(&mut *self.crashed.lock().unwrap(), true);
+            if first {
+                panic!("crashing the miner thread");
+            }
+            self.recorder.round_finished(miner_work, result);
+        }
+    }
+
+    #[test]
+    fn restarts_a_crashed_miner_thread() {
+        let plots = folder_plots("plots");
+        let (rounds, rounds_recv) = channel();
+        let submitter = CrashOnce {
+            crashed: Arc::new(Mutex::new(false)),
+            recorder: RoundRecorder { rounds: rounds },
+        };
+        let (_, signature_sender) =
+            spawn_miner(String::from("miner"), plots, submitter, ReadErrors::new(2, 10));
+        // the round crashes the thread, its successor mines the block again
+        signature_sender.send(first_block()).unwrap();
+
+        let result = rounds_recv.recv_timeout(Duration::from_secs(30)).unwrap();
+        assert_eq!(result.nonces_read, 16);
+    }
+
+    #[test]
+    fn quarantines_the_plot_a_crash_happened_in() {
+        let plots = folder_plots("plots");
+        let path = plots[0].path.clone();
+        let shared_plots = Arc::new(Mutex::new(plots));
+        let errors = ReadErrors::new(2, 10);
+        let crashed_work = Arc::new(Mutex::new(None));
+        let miner_work = first_block();
+
+        let (thread_plots, thread_errors, thread_work, thread_path) =
+            (shared_plots.clone(), errors.clone(), crashed_work.clone(), path.clone());
+        let crash = thread::spawn(move || {
+            let (_sender, signature_recv) = channel();
+            let (rounds, _) = channel();
+            let submitter = RoundRecorder { rounds: rounds };
+            let mut round = Round::new(&submitter,
+                                       &PlotFiles,
+                                       &signature_recv,
+                                       miner_work,
+                                       &thread_errors,
+                                       &thread_plots,
+                                       &thread_work);
+            round.reading = Some(thread_path);
+            panic!("crashing while hashing a stagger");
+        });
+
+        assert!(crash.join().is_err());
+        assert!(errors.is_quarantined(&path));
+        assert_eq!(shared_plots.lock().unwrap()[0].usable_nonces(), 0);
+        assert_eq!(shared_plots.lock().unwrap()[1].usable_nonces(), 8);
+        let carried = crashed_work.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take();
+        assert_eq!(carried.map(|work| work.height), Some(480000));
+    }
+
+    /// Crashes the miner thread with the first nonce it submits, reports to the pool otherwise.
+    #[derive(Clone)]
+    struct CrashingPool {
+        pool: Pool,
+        crashed: Arc<Mutex<bool>>,
+    }
+
+    impl NonceSubmitter for CrashingPool {
+        fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+            if !::std::mem::replace(&mut *self.crashed.lock().unwrap(), true) {
+                panic!("crashing the miner thread");
+            }
+            Ok(String::new())
+        }
+
+        fn round_finished(&self, miner_work: &MinerWork, result: &RoundResult) {
+            self.pool.round_finished(miner_work, result);
+        }
+
+        fn progress(&self, miner_work: &MinerWork) {
+            NonceSubmitter::progress(&self.pool, miner_work);
+        }
+
+        fn round_crashed(&self, miner_work: &MinerWork) {
+            self.pool.round_crashed(miner_work);
+        }
+    }
+
+    #[test]
+    fn a_crashed_round_does_not_stall_the_watchdog() {
+        let progress = Progress::new();
+        let crashed = Arc::new(Mutex::new(false));
+        let submitter = CrashingPool {
+            pool: Pool::from_url(Url::parse("http://127.0.0.1:8124").unwrap())
+                .with_progress(progress.clone()),
+            crashed: crashed.clone(),
+        };
+        let plots = folder_plots("plots");
+        let (_, signature_sender) =
+            spawn_miner(String::from("miner crash"), plots, submitter, ReadErrors::new(2, 10));
+        let mut miner_work = first_block();
+        // any deadline is good enough to be submitted
+        miner_work.target_deadline = u32::max_value() as u64;
+        signature_sender.send(miner_work).unwrap();
+
+        let start = Instant::now();
+        while !*crashed.lock().unwrap() {
+            assert!(Instant::now() - start < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(10));
+        }
+        // long after the round, only the pool that is never polled here looks stalled
+        let later = Instant::now() + Duration::from_secs(3600);
+        while progress.stalled(later, Duration::from_secs(60)) != vec![String::from("pool")] {
+            assert!(Instant::now() - start < Duration::from_secs(10),
+                    "{:?}",
+                    progress.stalled(later, Duration::from_secs(60)));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Mines a block with `plots` read from `source` through `backend` and returns the result and
+    /// the plots afterwards.
+    fn mine_here(backend: ReadBackend,
+                 source: &ScoopSource,
+                 errors: &ReadErrors)
+                 -> (RoundResult, Vec<Plot>) {
+        let mut plots = folder_plots("plots");
+        for plot in &mut plots {
+            plot.read_backend = backend;
+        }
+        let shared_plots = Arc::new(Mutex::new(plots));
+        let (signature_sender, signature_recv) = channel();
+        signature_sender.send(first_block()).unwrap();
+        // mine returns after the block, nothing else is coming
+        drop(signature_sender);
+        let (rounds, rounds_recv) = channel();
+        mine(RoundRecorder { rounds: rounds },
+             source,
+             &signature_recv,
+             shared_plots.clone(),
+             errors.clone(),
+             &Mutex::new(None));
+        let plots = shared_plots.lock().unwrap().clone();
+        (rounds_recv.recv().unwrap(), plots)
+    }
+
+    #[test]
+    fn retries_then_skips_a_stagger_that_fails_to_read() {
+        let failing = Path::new("plots/15250033402865692318_0_8_2");
+        let source = FailingReads::new().fail_staggers(failing, &[1]);
+
+        for &backend in &[ReadBackend::Mmap, ReadBackend::Uring] {
+            let errors = ReadErrors::new(2, 10);
+            let (result, plots) = mine_here(backend, &source, &errors);
+
+            // every stagger but the failing one is mined, the plot stays in
+            assert_eq!(result.nonces_read, 14, "{:?}", backend);
+            assert_eq!(errors.plot_count(failing),
+                       ErrorCount {
+                           retried: 2,
+                           skipped_staggers: 1,
+                       },
+                       "{:?}",
+                       backend);
+            assert!(plots.iter().all(|plot| plot.health == PlotHealth::Healthy),
+                    "{:?}",
+                    backend);
+        }
+    }
+
+    #[test]
+    fn quarantines_a_plot_that_keeps_failing() {
+        let failing = Path::new("plots/15250033402865692318_0_8_2");
+        let source = FailingReads::new().fail_staggers(failing, &[0, 1, 2, 3]);
+
+        for &backend in &[ReadBackend::Mmap, ReadBackend::Uring] {
+            let errors = ReadErrors::new(1, 2);
+            let (result, plots) = mine_here(backend, &source, &errors);
+
+            assert_eq!(result.nonces_read, 8, "{:?}", backend);
+            assert!(errors.is_quarantined(failing), "{:?}", backend);
+            match plots[0].health {
+                PlotHealth::Unusable(ref reason) => {
+                    assert!(reason.starts_with("read failed: quarantined after 2 skipped staggers"),
+                            "{:?}: {}",
+                            backend,
+                            reason)
+                }
+                ref health => panic!("{:?}: {:?}", backend, health),
+            }
+            assert_eq!(plots[1].health, PlotHealth::Healthy, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn keeps_mining_when_a_plot_fails() {
+        let dir = TempDir::new("failing");
+        for name in &["15250033402865692318_0_8_2", "15250033402865692318_8_8_2"] {
+            fs::copy(Path::new("plots").join(name), dir.join(name)).unwrap();
+        }
+        let plots = folder_plots(dir.path().to_str().unwrap());
+        // truncated after the scan found it intact, before the scoops of the first stagger
+        OpenOptions::new()
+            .write(true)
+            .open(dir.join("15250033402865692318_0_8_2"))
+            .unwrap()
+            .set_len(4096)
+            .unwrap();
+
+        let (rounds, rounds_recv) = channel();
+        let (shared_plots, signature_sender) =
+            spawn_miner(String::from("miner"),
+                        plots,
+                        RoundRecorder { rounds: rounds },
+                        ReadErrors::new(2, 10));
+        signature_sender.send(first_block()).unwrap();
+        let result = rounds_recv.recv().unwrap();
+        let health: Vec<PlotHealth> =
+            shared_plots.lock().unwrap().iter().map(|plot| plot.health.clone()).collect();
+
+        assert_eq!(result.nonces_read, 8);
+        assert_eq!(health,
+                   vec![PlotHealth::Unusable(String::from("read failed: past end of file")),
+                        PlotHealth::Healthy]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,274 @@
+use capture::{self, CaptureEntry};
+use cli::Args;
+use hyper::Url;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Submission {
+    pub account_id: u64,
+    pub nonce: u64,
+    /// index into the script of the mining info being served when the nonce arrived
+    pub block: usize,
+}
+
+/// A canned answer, `status` is `None` for requests the pool never answered.
+#[derive(Debug, Clone)]
+pub struct ScriptedResponse {
+    pub status: Option<u16>,
+    pub body: String,
+}
+
+impl ScriptedResponse {
+    pub fn ok(body: String) -> ScriptedResponse {
+        ScriptedResponse {
+            status: Some(200),
+            body: body,
+        }
+    }
+
+    fn from_capture(entry: &CaptureEntry) -> ScriptedResponse {
+        ScriptedResponse {
+            status: entry.status,
+            body: entry.response.clone().unwrap_or_default(),
+        }
+    }
+}
+
+struct MockPoolState {
+    mining_infos: Vec<ScriptedResponse>,
+    served: usize,
+    submit_responses: Vec<ScriptedResponse>,
+    submissions: Vec<Submission>,
+    /// get every submission as it arrives
+    subscribers: Vec<Sender<Submission>>,
+}
+
+/// A tiny HTTP server that speaks the subset of the burst pool protocol used by `pool::Pool`.
+///
+/// Every `getMiningInfo` request is answered with the next entry of the script, the last
+/// entry is repeated once the script runs out. Every `submitNonce` request is recorded and
+/// answered with the next scripted submit response, or a plain success once those run out.
+#[derive(Clone)]
+pub struct MockPool {
+    url: Url,
+    state: Arc<Mutex<MockPoolState>>,
+}
+
+impl MockPool {
+    #[cfg(test)]
+    pub fn start(script: Vec<String>) -> MockPool {
+        MockPool::serve("127.0.0.1:0",
+                        script.into_iter().map(ScriptedResponse::ok).collect(),
+                        Vec::new())
+            .unwrap()
+    }
+
+    /// Serves the responses of a capture file in the order the real pool sent them.
+    pub fn from_capture(listen: &str, capture_path: &Path) -> Result<MockPool, String> {
+        let entries = try!(capture::read(capture_path));
+        let scripted = |request_type: &str| -> Vec<ScriptedResponse> {
+            entries.iter()
+                .filter(|entry| entry.request_type == request_type)
+                .map(ScriptedResponse::from_capture)
+                .collect()
+        };
+        let mining_infos = scripted("getMiningInfo");
+        if mining_infos.is_empty() {
+            return Err(format!("no getMiningInfo responses in {:?}", capture_path));
+        }
+        MockPool::serve(listen, mining_infos, scripted("submitNonce"))
+    }
+
+    fn serve(listen: &str,
+             mining_infos: Vec<ScriptedResponse>,
+             submit_responses: Vec<ScriptedResponse>)
+             -> Result<MockPool, String> {
+        assert!(!mining_infos.is_empty(), "mock pool needs at least one mining info");
+        let listener = try!(TcpListener::bind(listen)
+            .map_err(|e| format!("listening on {}: {}", listen, e)));
+        let url = Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap();
+        let mock = MockPool {
+            url: url,
+            state: Arc::new(Mutex::new(MockPoolState {
+                mining_infos: mining_infos,
+                served: 0,
+                submit_responses: submit_responses,
+                submissions: Vec::new(),
+                subscribers: Vec::new(),
+            })),
+        };
+
+        let mock_ref = mock.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => mock_ref.handle(stream),
+                    Err(e) => warn!("mock pool accept: {:?}", e),
+                }
+            }
+        });
+        Ok(mock)
+    }
+
+    pub fn url(&self) -> Url {
+        self.url.clone()
+    }
+
+    #[cfg(test)]
+    pub fn submissions(&self) -> Vec<Submission> {
+        self.state.lock().unwrap().submissions.clone()
+    }
+
+    /// Hands out the submissions that arrive from now on.
+    pub fn subscribe(&self) -> Receiver<Submission> {
+        let (sender, receiver) = channel();
+        self.state.lock().unwrap().subscribers.push(sender);
+        receiver
+    }
+
+    fn handle(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).is_err() {
+            return;
+        }
+        // drain the headers, the pool protocol never sends a body
+        loop {
+            let mut header = String::new();
+            match reader.read_line(&mut header) {
+                Ok(0) | Err(_) => break,
+                Ok(_) if header.trim().is_empty() => break,
+                Ok(_) => {}
+            }
+        }
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let response = match Url::parse(&format!("http://mock{}", target)) {
+            Ok(request_url) => self.respond(&request_url),
+            Err(_) => {
+                ScriptedResponse {
+                    status: Some(400),
+                    body: String::from("{\"errorCode\":1}"),
+                }
+            }
+        };
+
+        let status = match response.status {
+            Some(status) => status,
+            None => return, // hang up like the real pool did
+        };
+        let mut stream = reader.into_inner();
+        let _ = write!(stream,
+                       "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: \
+                        {}\r\nConnection: close\r\n\r\n{}",
+                       status,
+                       reason(status),
+                       response.body.len(),
+                       response.body);
+    }
+
+    fn respond(&self, request_url: &Url) -> ScriptedResponse {
+        let param = |name: &str| {
+            request_url.query_pairs()
+                .find(|&(ref key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+        };
+
+        let mut state = self.state.lock().unwrap();
+        match param("requestType").as_ref().map(|s| s.as_str()) {
+            Some("getMiningInfo") => {
+                let index = state.served.min(state.mining_infos.len() - 1);
+                state.served += 1;
+                state.mining_infos[index].clone()
+            }
+            Some("submitNonce") => {
+                let account_id = param("accountId").and_then(|s| s.parse::<u64>().ok());
+                let nonce = param("nonce").and_then(|s| s.parse::<u64>().ok());
+                match (account_id, nonce) {
+                    (Some(account_id), Some(nonce)) => {
+                        let block = state.served
+                            .saturating_sub(1)
+                            .min(state.mining_infos.len() - 1);
+                        let index = state.submissions.len();
+                        let submission = Submission {
+                            account_id: account_id,
+                            nonce: nonce,
+                            block: block,
+                        };
+                        state.subscribers
+                            .retain(|subscriber| subscriber.send(submission.clone()).is_ok());
+                        state.submissions.push(submission);
+                        match state.submit_responses.get(index) {
+                            Some(response) => response.clone(),
+                            None => ScriptedResponse::ok(String::from("{\"result\":\"success\"}")),
+                        }
+                    }
+                    _ => {
+                        ScriptedResponse {
+                            status: Some(400),
+                            body: String::from("{\"errorCode\":3}"),
+                        }
+                    }
+                }
+            }
+            _ => {
+                ScriptedResponse {
+                    status: Some(404),
+                    body: String::from("{\"errorCode\":1}"),
+                }
+            }
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+/// `mock-pool <capture_file> [--listen <address>]`
+///
+/// Plays back a pool recorded with `capture_file` so a miner can be pointed at it offline.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse_plain(args, &["listen"]));
+    if args.positional.len() != 1 {
+        return Err(String::from("expected <capture_file>"));
+    }
+    let listen = try!(args.option("listen", String::from("127.0.0.1:8124")));
+
+    let mock = try!(MockPool::from_capture(&listen, Path::new(&args.positional[0])));
+    let submissions = mock.subscribe();
+    println!("serving {} at {}", args.positional[0], mock.url());
+    for submission in submissions {
+        println!("submission: account {} nonce {} during block {}",
+                 submission.account_id,
+                 submission.nonce,
+                 submission.block);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub fn mining_info_json(generation_signature: &str,
+                        base_target: u64,
+                        height: u64,
+                        target_deadline: u64)
+                        -> String {
+    format!("{{\"generationSignature\":\"{}\",\"baseTarget\":{},\"requestProcessingTime\":0,\
+             \"height\":{},\"targetDeadline\":{}}}",
+            generation_signature,
+            base_target,
+            height,
+            target_deadline)
+}
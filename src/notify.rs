@@ -0,0 +1,263 @@
+use libc;
+use std::collections::BTreeMap;
+use std::env;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Sends state changes to systemd over `$NOTIFY_SOCKET`, see sd_notify(3).
+pub struct Notifier {
+    fd: libc::c_int,
+    addr: libc::sockaddr_un,
+    addr_len: libc::socklen_t,
+}
+
+impl Notifier {
+    /// The notifier of the socket systemd passed in, if the miner runs as a notify service.
+    pub fn from_env() -> Result<Option<Notifier>, String> {
+        match env::var("NOTIFY_SOCKET") {
+            Ok(path) => Notifier::connect(&path).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// `path` is a file system path or, starting with `@`, an abstract socket name.
+    pub fn connect(path: &str) -> Result<Notifier, String> {
+        let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+        addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+        let bytes = path.as_bytes();
+        if bytes.is_empty() || bytes.len() >= addr.sun_path.len() {
+            return Err(format!("bad NOTIFY_SOCKET {:?}", path));
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            addr.sun_path[i] = byte as libc::c_char;
+        }
+        if bytes[0] == b'@' {
+            addr.sun_path[0] = 0;
+        }
+        let path_offset = mem::size_of::<libc::sa_family_t>();
+        let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+        if fd < 0 {
+            return Err(format!("notify socket: {}", io::Error::last_os_error()));
+        }
+        Ok(Notifier {
+            fd: fd,
+            addr: addr,
+            addr_len: (path_offset + bytes.len()) as libc::socklen_t,
+        })
+    }
+
+    /// Sends newline separated assignments such as `READY=1`.
+    pub fn notify(&self, state: &str) -> Result<(), String> {
+        let sent = unsafe {
+            libc::sendto(self.fd,
+                         state.as_ptr() as *const libc::c_void,
+                         state.len(),
+                         libc::MSG_NOSIGNAL,
+                         &self.addr as *const libc::sockaddr_un as *const libc::sockaddr,
+                         self.addr_len)
+        };
+        if sent < 0 {
+            return Err(format!("notifying systemd: {}", io::Error::last_os_error()));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.fd);
+        }
+    }
+}
+
+struct ProgressState {
+    /// last time the pool thread went through its polling loop
+    pool: Instant,
+    /// last sign of life of every miner thread in the middle of a round
+    miners: BTreeMap<String, Instant>,
+    height: Option<u64>,
+    /// best deadline in seconds found for the current block
+    best_deadline: Option<u64>,
+}
+
+/// What the pool and the miner threads got done lately, so the watchdog only gets pinged while
+/// they are getting somewhere.
+#[derive(Clone)]
+pub struct Progress {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+impl Progress {
+    pub fn new() -> Progress {
+        Progress {
+            state: Arc::new(Mutex::new(ProgressState {
+                pool: Instant::now(),
+                miners: BTreeMap::new(),
+                height: None,
+                best_deadline: None,
+            })),
+        }
+    }
+
+    pub fn pool_alive(&self) {
+        self.state.lock().unwrap().pool = Instant::now();
+    }
+
+    pub fn new_block(&self, height: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.height = Some(height);
+        state.best_deadline = None;
+    }
+
+    /// A miner thread is reading, `miner` is its thread name.
+    pub fn miner_busy(&self, miner: &str) {
+        self.state.lock().unwrap().miners.insert(miner.to_owned(), Instant::now());
+    }
+
+    /// A miner thread crashed, the one that takes its place reports for itself.
+    pub fn miner_crashed(&self, miner: &str) {
+        self.state.lock().unwrap().miners.remove(miner);
+    }
+
+    /// A miner thread finished its round and waits for the next block, which can take as long
+    /// as it takes.
+    pub fn miner_idle(&self, miner: &str, height: u64, deadline: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        state.miners.remove(miner);
+        if state.height != Some(height) {
+            return;
+        }
+        if let Some(deadline) = deadline {
+            if state.best_deadline.map_or(true, |best| deadline < best) {
+                state.best_deadline = Some(deadline);
+            }
+        }
+    }
+
+    /// Whether the mining info came in.
+    pub fn ready(&self) -> bool {
+        self.state.lock().unwrap().height.is_some()
+    }
+
+    pub fn status(&self) -> String {
+        let state = self.state.lock().unwrap();
+        match (state.height, state.best_deadline) {
+            (None, _) => String::from("waiting for mining info"),
+            (Some(height), None) => format!("height {}, no deadline yet", height),
+            (Some(height), Some(deadline)) => {
+                format!("height {}, best deadline {}s", height, deadline)
+            }
+        }
+    }
+
+    /// The threads that showed no progress for longer than `limit`.
+    pub fn stalled(&self, now: Instant, limit: Duration) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut stalled = Vec::new();
+        if now.duration_since(state.pool) > limit {
+            stalled.push(String::from("pool"));
+        }
+        for (miner, &last) in &state.miners {
+            if now.duration_since(last) > limit {
+                stalled.push(miner.clone());
+            }
+        }
+        stalled
+    }
+}
+
+/// Reports readiness and status to systemd and pings the watchdog while `progress` shows that
+/// the pool and the miner threads are working.
+pub fn start(notifier: Notifier, progress: Progress) {
+    // systemd wants a ping at least every WATCHDOG_USEC, half of that leaves some slack
+    let watchdog = env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .map(|usec| Duration::from_millis(usec / 1000));
+    let interval = match watchdog {
+        Some(watchdog) if watchdog < Duration::from_secs(10) => watchdog / 2,
+        _ => Duration::from_secs(5),
+    };
+
+    thread::spawn(move || {
+        let mut ready = false;
+        let mut last_status = String::new();
+        let mut last_stalled = Vec::new();
+        loop {
+            let mut state = Vec::new();
+            if !ready && progress.ready() {
+                ready = true;
+                state.push(String::from("READY=1"));
+            }
+            let status = progress.status();
+            if status != last_status {
+                state.push(format!("STATUS={}", status));
+                last_status = status;
+            }
+            if let Some(watchdog) = watchdog {
+                let stalled = progress.stalled(Instant::now(), watchdog);
+                if stalled.is_empty() {
+                    state.push(String::from("WATCHDOG=1"));
+                } else if stalled != last_stalled {
+                    warn!("no progress for {:?} in {}, not pinging the watchdog",
+                          watchdog,
+                          stalled.join(", "));
+                }
+                last_stalled = stalled;
+            }
+            if !state.is_empty() {
+                if let Err(e) = notifier.notify(&state.join("\n")) {
+                    error!("{}", e);
+                }
+            }
+            thread::sleep(interval);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+    use std::time::{Duration, Instant};
+    use testing::TempDir;
+
+    #[test]
+    fn sends_to_the_notify_socket() {
+        let dir = TempDir::new("notify");
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        let notifier = Notifier::connect(path.to_str().unwrap()).unwrap();
+        notifier.notify("READY=1\nSTATUS=height 480000, no deadline yet").unwrap();
+
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &b"READY=1\nSTATUS=height 480000, no deadline yet"[..]);
+    }
+
+    #[test]
+    fn tracks_status_and_stalled_threads() {
+        let progress = Progress::new();
+        assert!(!progress.ready());
+        progress.new_block(480000);
+        assert!(progress.ready());
+        assert_eq!(progress.status(), "height 480000, no deadline yet");
+
+        progress.miner_busy("miner sda/0");
+        progress.miner_busy("miner sdb/0");
+        progress.miner_idle("miner sda/0", 480000, Some(3600));
+        progress.miner_idle("miner sdb/0", 479999, Some(60));
+        assert_eq!(progress.status(), "height 480000, best deadline 3600s");
+
+        let limit = Duration::from_secs(30);
+        assert!(progress.stalled(Instant::now(), limit).is_empty());
+        progress.miner_busy("miner sda/0");
+        assert_eq!(progress.stalled(Instant::now() + Duration::from_secs(31), limit),
+                   vec!["pool", "miner sda/0"]);
+    }
+}
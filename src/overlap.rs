@@ -0,0 +1,198 @@
+use constants::*;
+use plots::PlotFolders;
+use std::path::PathBuf;
+
+/// Two plots of the same account that share a nonce range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Overlap {
+    /// the plot that keeps the range, it comes first in the configured folder order
+    pub kept: PathBuf,
+    pub redundant: PathBuf,
+    pub start_nonce: u64,
+    pub nonce_count: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverlapReport {
+    /// the same file found through two paths, the second one is redundant
+    pub same_files: Vec<(PathBuf, PathBuf)>,
+    pub overlaps: Vec<Overlap>,
+}
+
+impl OverlapReport {
+    pub fn is_empty(&self) -> bool {
+        self.same_files.is_empty() && self.overlaps.is_empty()
+    }
+
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for &(ref kept, ref redundant) in &self.same_files {
+            lines.push(format!("same plot file found twice: {:?} and {:?}", kept, redundant));
+        }
+        for overlap in &self.overlaps {
+            lines.push(format!("{:?} overlaps {:?} by {} nonces ({} MiB) starting at nonce {}",
+                               overlap.redundant,
+                               overlap.kept,
+                               overlap.nonce_count,
+                               overlap.nonce_count * PLOT_SIZE as u64 / 1024 / 1024,
+                               overlap.start_nonce));
+        }
+        lines
+    }
+}
+
+/// Finds plots that are the same file and plots whose nonce ranges overlap across all folders.
+pub fn analyze(plot_folders: &PlotFolders) -> OverlapReport {
+    let mut report = OverlapReport {
+        same_files: Vec::new(),
+        overlaps: Vec::new(),
+    };
+    let plots: Vec<_> = plot_folders.folders
+        .iter()
+        .flat_map(|folder| folder.plots.iter())
+        .collect();
+
+    for (i, plot) in plots.iter().enumerate() {
+        let earlier = &plots[..i];
+        if let Some(first) = earlier.iter().find(|other| {
+            plot.file_id.is_some() && other.file_id == plot.file_id
+        }) {
+            report.same_files.push((first.path.clone(), plot.path.clone()));
+            continue;
+        }
+
+        let start = plot.start_nonce;
+        let end = plot.start_nonce.saturating_add(plot.usable_nonces());
+        for other in earlier {
+            if other.account_id != plot.account_id {
+                continue;
+            }
+            // a duplicate path of an earlier file was already reported above
+            if report.same_files.iter().any(|&(_, ref redundant)| *redundant == other.path) {
+                continue;
+            }
+            let other_end = other.start_nonce.saturating_add(other.usable_nonces());
+            let overlap_start = start.max(other.start_nonce);
+            let overlap_end = end.min(other_end);
+            if overlap_start < overlap_end {
+                report.overlaps.push(Overlap {
+                    kept: other.path.clone(),
+                    redundant: plot.path.clone(),
+                    start_nonce: overlap_start,
+                    nonce_count: overlap_end - overlap_start,
+                });
+            }
+        }
+    }
+    report
+}
+
+/// Stops mining what another plot already covers: duplicate paths are dropped and overlapping
+/// ranges are excluded from the later plot.
+pub fn exclude_redundant(plot_folders: &mut PlotFolders, report: &OverlapReport) {
+    for folder in &mut plot_folders.folders {
+        folder.plots.retain(|plot| {
+            !report.same_files.iter().any(|&(_, ref redundant)| *redundant == plot.path)
+        });
+        for plot in &mut folder.plots {
+            let mut excluded: Vec<(u64, u64)> = report.overlaps
+                .iter()
+                .filter(|overlap| overlap.redundant == plot.path)
+                .map(|overlap| (overlap.start_nonce, overlap.start_nonce + overlap.nonce_count))
+                .collect();
+            excluded.sort();
+
+            // merge touching ranges so whole staggers spanning two overlaps are skipped too
+            let mut merged: Vec<(u64, u64)> = Vec::new();
+            for (start, end) in excluded {
+                match merged.last_mut() {
+                    Some(last) if start <= last.1 => {
+                        if end > last.1 {
+                            last.1 = end;
+                        }
+                        continue;
+                    }
+                    _ => {}
+                }
+                merged.push((start, end));
+            }
+            plot.excluded = merged;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots::{Plot, PlotFolder, PlotFolders};
+    use std::path::{Path, PathBuf};
+
+    fn plot(name: &str, file_id: u64) -> Plot {
+        let mut plot = Plot::from_path(Path::new(name)).unwrap().unwrap();
+        plot.file_id = Some((1, file_id));
+        plot
+    }
+
+    fn folders(plots: Vec<Vec<Plot>>) -> PlotFolders {
+        PlotFolders {
+            folders: plots.into_iter()
+                .map(|plots| {
+                    PlotFolder {
+                        path: PathBuf::from("folder"),
+                        plots: plots,
+                        entries: Vec::new(),
+                        notes: Vec::new(),
+                        read_threads: None,
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn finds_overlaps_across_folders() {
+        let mut plot_folders = folders(vec![vec![plot("/a/1_0_8_2", 1), plot("/a/2_0_8_2", 2)],
+                                            vec![plot("/b/1_4_8_2", 3),
+                                                 plot("/b/1_16_8_2", 4),
+                                                 plot("/b/1_12_8_2", 5)],
+                                            vec![plot("/c/1_0_8_2", 1)]]);
+        let report = analyze(&plot_folders);
+        assert_eq!(report.same_files,
+                   vec![(PathBuf::from("/a/1_0_8_2"), PathBuf::from("/c/1_0_8_2"))]);
+        assert_eq!(report.overlaps,
+                   vec![Overlap {
+                            kept: PathBuf::from("/a/1_0_8_2"),
+                            redundant: PathBuf::from("/b/1_4_8_2"),
+                            start_nonce: 4,
+                            nonce_count: 4,
+                        },
+                        Overlap {
+                            kept: PathBuf::from("/b/1_16_8_2"),
+                            redundant: PathBuf::from("/b/1_12_8_2"),
+                            start_nonce: 16,
+                            nonce_count: 4,
+                        }]);
+
+        exclude_redundant(&mut plot_folders, &report);
+        assert!(plot_folders.folders[2].plots.is_empty());
+        let partly_redundant = &plot_folders.folders[1].plots[0];
+        assert_eq!(partly_redundant.excluded, vec![(4, 8)]);
+        assert!(partly_redundant.is_excluded(4, 6));
+        assert!(partly_redundant.is_excluded(6, 8));
+        assert!(!partly_redundant.is_excluded(8, 10));
+        assert_eq!(plot_folders.folders[1].plots[2].excluded, vec![(16, 20)]);
+    }
+
+    #[test]
+    fn merges_touching_exclusions() {
+        let mut plot_folders = folders(vec![vec![plot("/a/1_0_4_4", 1),
+                                                 plot("/a/1_4_4_4", 2),
+                                                 plot("/b/1_0_8_8", 3)]]);
+        let report = analyze(&plot_folders);
+        assert_eq!(report.overlaps.len(), 2);
+        exclude_redundant(&mut plot_folders, &report);
+        let big = &plot_folders.folders[0].plots[2];
+        assert_eq!(big.excluded, vec![(0, 8)]);
+        assert!(big.is_excluded(0, 8));
+    }
+}
@@ -1,9 +1,15 @@
 use constants::*;
+use glob;
+use overlap;
+use reader::{self, ReadBackend};
 use regex::Regex;
 use sph_shabal;
-use std::path::PathBuf;
+use throttle::{Throttle, Throttles};
+use uring;
+use std::collections::BTreeMap;
 use std::fs;
-use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use libc;
 
 #[derive(Clone)]
@@ -13,81 +19,540 @@ pub struct Plot {
     pub start_nonce: u64,
     pub nonce_count: u64,
     pub stagger_size: u64,
+    pub health: PlotHealth,
+    /// device and inode, to spot the same file reached through two paths
+    pub file_id: Option<(u64, u64)>,
+    /// nonce ranges `[start, end)` another plot already covers
+    pub excluded: Vec<(u64, u64)>,
+    pub read_backend: ReadBackend,
+    /// plots with a higher priority are read first by their miner thread
+    pub priority: u32,
+    /// shared with the other plots of its folder when the folder is rate limited
+    pub throttle: Option<Throttle>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlotHealth {
+    Healthy,
+    /// only the first `usable_nonces` nonces can be mined
+    Degraded {
+        usable_nonces: u64,
+        reason: String,
+    },
+    Unusable(String),
+}
+
+// compiled once per thread that scans, instead of for every file
+thread_local!(static PLOT_NAME: Regex = Regex::new(r"^(\d+)_(\d+)_(\d+)_(\d+)$").unwrap());
+
+impl Plot {
+    /// Parses a plot file name of the form `{account_id}_{start_nonce}_{nonce_count}_{stagger_size}`.
+    ///
+    /// Returns `Ok(None)` for files that aren't named like a plot at all and an error for names
+    /// that look like a plot but can't be one.
+    pub fn from_path(plot_path: &Path) -> Result<Option<Plot>, String> {
+        let filename = match plot_path.file_name().map(|name| name.to_str()) {
+            Some(Some(filename)) => filename,
+            Some(None) => return Err(String::from("file name is not valid UTF-8")),
+            None => return Ok(None),
+        };
+
+        let captures = match PLOT_NAME.with(|plot_name| plot_name.captures(filename)) {
+            Some(captures) => captures,
+            None => return Ok(None),
+        };
+        let field = |i: usize, name: &str| -> Result<u64, String> {
+            let value = captures.at(i).unwrap();
+            value.parse::<u64>().map_err(|_| format!("{} {} does not fit into 64 bits", name, value))
+        };
+        Ok(Some(Plot {
+            path: plot_path.to_path_buf(),
+            account_id: try!(field(1, "account id")),
+            start_nonce: try!(field(2, "start nonce")),
+            nonce_count: try!(field(3, "nonce count")),
+            stagger_size: try!(field(4, "stagger size")),
+            health: PlotHealth::Healthy,
+            file_id: None,
+            excluded: Vec::new(),
+            read_backend: ReadBackend::Mmap,
+            priority: 0,
+            throttle: None,
+        }))
+    }
+
+    /// Checks the numbers in the name against each other and against the size of the file.
+    pub fn check_geometry(&mut self, file_len: u64) {
+        self.health = self.geometry_health(file_len);
+    }
+
+    fn geometry_health(&self, file_len: u64) -> PlotHealth {
+        if self.nonce_count == 0 {
+            return PlotHealth::Unusable(String::from("nonce count is 0"));
+        }
+        if self.stagger_size == 0 {
+            return PlotHealth::Unusable(String::from("stagger size is 0"));
+        }
+        let stagger_len = match self.stagger_size.checked_mul(PLOT_SIZE as u64) {
+            Some(stagger_len) => stagger_len,
+            None => return PlotHealth::Unusable(String::from("stagger size is too large")),
+        };
+        let expected_len = match self.nonce_count.checked_mul(PLOT_SIZE as u64) {
+            Some(expected_len) => expected_len,
+            None => return PlotHealth::Unusable(String::from("nonce count is too large")),
+        };
+
+        // mine reads whole staggers, a partial one at the end can't be mined
+        let full_staggers = self.nonce_count / self.stagger_size * self.stagger_size;
+        let on_disk = file_len / stagger_len * self.stagger_size;
+
+        if file_len < expected_len {
+            let usable = full_staggers.min(on_disk);
+            if usable == 0 {
+                return PlotHealth::Unusable(format!("file is {} bytes, expected {}",
+                                                    file_len,
+                                                    expected_len));
+            }
+            return PlotHealth::Degraded {
+                usable_nonces: usable,
+                reason: format!("file is {} bytes, expected {}, mining {} of {} nonces",
+                                file_len,
+                                expected_len,
+                                usable,
+                                self.nonce_count),
+            };
+        }
+        if full_staggers != self.nonce_count {
+            return PlotHealth::Degraded {
+                usable_nonces: full_staggers,
+                reason: format!("nonce count {} is not a multiple of stagger size {}, the last \
+                                 {} nonces can't be mined",
+                                self.nonce_count,
+                                self.stagger_size,
+                                self.nonce_count - full_staggers),
+            };
+        }
+        if file_len > expected_len {
+            return PlotHealth::Degraded {
+                usable_nonces: self.nonce_count,
+                reason: format!("file is {} bytes longer than its name says",
+                                file_len - expected_len),
+            };
+        }
+        PlotHealth::Healthy
+    }
+
+    /// Whether every nonce in `[start, end)` is covered by another plot.
+    pub fn is_excluded(&self, start: u64, end: u64) -> bool {
+        self.excluded.iter().any(|&(excluded_start, excluded_end)| {
+            excluded_start <= start && end <= excluded_end
+        })
+    }
+
+    /// How many nonces from the start of the plot can be mined.
+    pub fn usable_nonces(&self) -> u64 {
+        match self.health {
+            PlotHealth::Healthy => self.nonce_count,
+            PlotHealth::Degraded { usable_nonces, .. } => usable_nonces,
+            PlotHealth::Unusable(_) => 0,
+        }
+    }
+
+    /// Byte offset of one scoop of a nonce inside the plot file, `None` if the nonce isn't in
+    /// this plot.
+    pub fn scoop_offset(&self, nonce: u64, scoop_num: u16) -> Option<u64> {
+        if self.stagger_size == 0 || nonce < self.start_nonce ||
+           nonce - self.start_nonce >= self.nonce_count {
+            return None;
+        }
+        let nonce_in_plot = nonce - self.start_nonce;
+        let stagger = nonce_in_plot / self.stagger_size;
+        let nonce_in_stagger = nonce_in_plot % self.stagger_size;
+        let scoop_size = HASH_SIZE as u64 * 2;
+        Some(stagger * self.stagger_size * PLOT_SIZE as u64 +
+             scoop_num as u64 * self.stagger_size * scoop_size +
+             nonce_in_stagger * scoop_size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanStatus {
+    Plot,
+    /// a plot that can only be mined in part
+    Degraded(String),
+    Ignored(String),
+    Invalid(String),
+}
+
+/// What the scan made of one folder or folder entry.
+#[derive(Debug, Clone)]
+pub struct ScanEntry {
+    pub path: PathBuf,
+    pub status: ScanStatus,
 }
 
 pub struct PlotFolder {
     pub path: PathBuf,
     pub plots: Vec<Plot>,
+    pub entries: Vec<ScanEntry>,
+    /// findings about the folder as a whole
+    pub notes: Vec<String>,
+    /// miner threads the folder asks for on its device
+    pub read_threads: Option<u32>,
 }
 
 pub struct PlotFolders {
     pub folders: Vec<PlotFolder>,
 }
 
-pub fn get_plots(folder_paths: Vec<String>) -> PlotFolders {
-    PlotFolders {
-        folders: folder_paths.into_iter()
-            .map(|folder_path| {
-                let path_buf = PathBuf::from(folder_path);
-                let plot_paths = match path_buf.is_dir() {
-                        true => {
-                            let combine_paths =
-                                |file_path: Result<fs::DirEntry, io::Error>| -> PathBuf {
-                                    path_buf.as_path()
-                                        .join(file_path.unwrap().path())
-                                };
-                            Ok(path_buf.read_dir()
-                                .unwrap()
-                                .map(combine_paths)
-                                .collect::<Vec<PathBuf>>())
-                        }
-                        false => Err(format!("plot folder '{:?}' is not a folder", path_buf)),
+impl PlotFolders {
+    pub fn plot_count(&self) -> usize {
+        self.folders.iter().map(|folder| folder.plots.len()).sum()
+    }
+
+    /// What the scan found, one line per folder and one per entry that isn't a plot.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        for folder in &self.folders {
+            let count = |wanted: fn(&ScanStatus) -> bool| {
+                folder.entries.iter().filter(|entry| wanted(&entry.status)).count()
+            };
+            lines.push(format!("plot folder {:?}: {} plots, {} degraded, {} ignored, {} invalid",
+                               folder.path,
+                               folder.plots.len(),
+                               count(|status| match *status {
+                                   ScanStatus::Degraded(_) => true,
+                                   _ => false,
+                               }),
+                               count(|status| match *status {
+                                   ScanStatus::Ignored(_) => true,
+                                   _ => false,
+                               }),
+                               count(|status| match *status {
+                                   ScanStatus::Invalid(_) => true,
+                                   _ => false,
+                               })));
+            for note in &folder.notes {
+                lines.push(format!("  {}", note));
+            }
+            for entry in &folder.entries {
+                match entry.status {
+                    ScanStatus::Plot => {}
+                    ScanStatus::Degraded(ref reason) => {
+                        lines.push(format!("  degraded {:?}: {}", entry.path, reason))
+                    }
+                    ScanStatus::Ignored(ref reason) => {
+                        lines.push(format!("  ignored {:?}: {}", entry.path, reason))
+                    }
+                    ScanStatus::Invalid(ref reason) => {
+                        lines.push(format!("  invalid {:?}: {}", entry.path, reason))
                     }
-                    .unwrap();
-                let plot_regex = Regex::new(r"^(\d+)_(\d+)_(\d+)_(\d+)$").unwrap();
-
-                let plots = plot_paths.iter()
-                    .map(|plot_path| {
-                        let filename = plot_path.as_path().file_name().unwrap();
-
-                        match plot_regex.captures(filename.to_str().unwrap()) {
-                                Some(captures) => {
-                                    Ok(Plot {
-                                        path: plot_path.clone(),
-                                        account_id: captures.at(1)
-                                            .unwrap()
-                                            .parse::<u64>()
-                                            .unwrap(),
-                                        start_nonce: captures.at(2)
-                                            .unwrap()
-                                            .parse::<u64>()
-                                            .unwrap(),
-                                        nonce_count: captures.at(3)
-                                            .unwrap()
-                                            .parse::<u64>()
-                                            .unwrap(),
-                                        stagger_size: captures.at(4)
-                                            .unwrap()
-                                            .parse::<u64>()
-                                            .unwrap(),
-                                    })
-                                }
-                                None => Err("invalid plot file name"),
-                            }
-                            .unwrap()
-                    })
-                    .collect();
-
-                PlotFolder {
-                    path: path_buf,
-                    plots: plots,
                 }
-            })
-            .collect(),
+            }
+        }
+        lines
     }
 }
 
-#[allow(dead_code)]
+/// How plot folders are found and scanned.
+#[derive(Clone)]
+pub struct ScanOptions {
+    /// how many levels of subfolders are scanned below each plot folder
+    pub max_depth: u32,
+    /// files and folders matching any of these are skipped
+    pub exclude: Vec<glob::Pattern>,
+    /// don't mine nonce ranges another plot already covers
+    pub exclude_overlaps: bool,
+    pub read_backend: ReadBackend,
+    /// options of single folders by their entry in `plot_folders`
+    pub folders: BTreeMap<String, FolderOptions>,
+    /// the throttles of rate limited folders, shared by every scan with these options
+    pub throttles: Throttles,
+}
+
+impl Default for ScanOptions {
+    fn default() -> ScanOptions {
+        ScanOptions {
+            max_depth: 0,
+            exclude: Vec::new(),
+            exclude_overlaps: false,
+            read_backend: ReadBackend::Mmap,
+            folders: BTreeMap::new(),
+            throttles: Throttles::default(),
+        }
+    }
+}
+
+impl ScanOptions {
+    fn excluded_by(&self, path: &Path) -> Option<&glob::Pattern> {
+        self.exclude.iter().find(|pattern| pattern.matches_path(path))
+    }
+
+    fn folder_options(&self, folder_path: &str) -> FolderOptions {
+        self.folders.get(folder_path).cloned().unwrap_or_default()
+    }
+}
+
+/// How the plots of one entry of `plot_folders` are mined.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FolderOptions {
+    /// instead of the read backend of all folders
+    pub read_backend: Option<ReadBackend>,
+    /// miner threads for the device the folder is on
+    pub read_threads: Option<u32>,
+    /// folders with a higher priority are read first
+    pub priority: u32,
+    /// MiB per second all miner threads together read from the folder at most
+    pub rate_limit: Option<u32>,
+    /// disabled folders aren't scanned
+    pub enabled: bool,
+    /// the accounts whose plots are mined, all if empty
+    pub accounts: Vec<u64>,
+}
+
+impl Default for FolderOptions {
+    fn default() -> FolderOptions {
+        FolderOptions {
+            read_backend: None,
+            read_threads: None,
+            priority: 0,
+            rate_limit: None,
+            enabled: true,
+            accounts: Vec::new(),
+        }
+    }
+}
+
+pub fn get_plots(folder_paths: Vec<String>, options: &ScanOptions) -> PlotFolders {
+    let mut folders = Vec::new();
+    for folder_path in folder_paths {
+        let folder_options = options.folder_options(&folder_path);
+        if !folder_options.enabled {
+            folders.push(PlotFolder {
+                path: PathBuf::from(folder_path),
+                plots: Vec::new(),
+                entries: Vec::new(),
+                notes: vec![String::from("disabled, not scanned")],
+                read_threads: None,
+            });
+            continue;
+        }
+        if !is_glob(&folder_path) {
+            folders.push(scan_folder(PathBuf::from(folder_path), options, &folder_options));
+            continue;
+        }
+
+        // every folder the pattern matches is mined like it was listed on its own
+        let matches: Vec<PathBuf> = match glob::glob(&folder_path) {
+            Ok(paths) => {
+                paths.filter_map(|path| path.ok())
+                    .filter(|path| path.is_dir())
+                    .collect()
+            }
+            Err(e) => {
+                folders.push(invalid_folder(PathBuf::from(&folder_path),
+                                            format!("invalid pattern: {}", e)));
+                continue;
+            }
+        };
+        if matches.is_empty() {
+            folders.push(invalid_folder(PathBuf::from(&folder_path),
+                                        String::from("pattern matches no folder")));
+        }
+        for path in matches {
+            folders.push(scan_folder(path, options, &folder_options));
+        }
+    }
+    PlotFolders { folders: folders }
+}
+
+pub fn is_glob(folder_path: &str) -> bool {
+    folder_path.contains('*') || folder_path.contains('?') || folder_path.contains('[')
+}
+
+/// Scans the folders and logs what was found, fails only when there is nothing to mine.
+///
+/// With `exclude_overlaps` nonce ranges that are already covered by another plot are not mined
+/// again, otherwise they are only reported.
+pub fn get_usable_plots(folder_paths: Vec<String>,
+                        options: &ScanOptions)
+                        -> Result<PlotFolders, String> {
+    let mut plot_folders = get_plots(folder_paths, options);
+    for line in plot_folders.report() {
+        info!("{}", line);
+    }
+
+    let overlaps = overlap::analyze(&plot_folders);
+    if !overlaps.is_empty() {
+        for line in overlaps.report() {
+            warn!("{}", line);
+        }
+        if options.exclude_overlaps {
+            overlap::exclude_redundant(&mut plot_folders, &overlaps);
+            info!("excluded redundant nonce ranges from mining");
+        }
+    }
+    match plot_folders.plot_count() {
+        0 => Err(String::from("no usable plots in any plot folder")),
+        _ => Ok(plot_folders),
+    }
+}
+
+fn invalid_folder(path_buf: PathBuf, reason: String) -> PlotFolder {
+    PlotFolder {
+        path: path_buf.clone(),
+        plots: Vec::new(),
+        entries: vec![ScanEntry {
+                          path: path_buf,
+                          status: ScanStatus::Invalid(reason),
+                      }],
+        notes: Vec::new(),
+        read_threads: None,
+    }
+}
+
+fn scan_folder(path_buf: PathBuf,
+               options: &ScanOptions,
+               folder_options: &FolderOptions)
+               -> PlotFolder {
+    if let Err(e) = path_buf.read_dir() {
+        let reason = match path_buf.is_file() {
+            true => String::from("not a folder"),
+            false => format!("can't read folder: {}", e),
+        };
+        return invalid_folder(path_buf, reason);
+    }
+
+    let mut plots = Vec::new();
+    let mut entries = Vec::new();
+    scan_dir(&path_buf, 0, options, folder_options, &mut plots, &mut entries);
+
+    // read_dir order is arbitrary, keep reports and mining order stable
+    plots.sort_by(|a: &Plot, b: &Plot| a.path.cmp(&b.path));
+    entries.sort_by(|a: &ScanEntry, b: &ScanEntry| a.path.cmp(&b.path));
+
+    let mut notes = Vec::new();
+    let mut read_backend = folder_options.read_backend.unwrap_or(options.read_backend);
+    if read_backend == ReadBackend::Uring && !plots.is_empty() && !uring::supported() {
+        notes.push(String::from("io_uring is not available, reading plots with direct I/O"));
+        read_backend = ReadBackend::Direct;
+    }
+    let throttle = folder_options.rate_limit
+        .map(|mib_per_sec| options.throttles.get(&path_buf, mib_per_sec));
+    let mut fallbacks = 0;
+    for plot in &mut plots {
+        plot.read_backend = read_backend;
+        plot.priority = folder_options.priority;
+        plot.throttle = throttle.clone();
+        // io_uring reads plots without direct I/O support through the page cache
+        if read_backend == ReadBackend::Direct && !reader::supports_direct(&plot.path) {
+            plot.read_backend = ReadBackend::Mmap;
+            fallbacks += 1;
+        }
+    }
+    if fallbacks > 0 {
+        notes.push(format!("direct I/O is not supported for {} plots, reading them through mmap",
+                           fallbacks));
+    }
+    PlotFolder {
+        path: path_buf,
+        plots: plots,
+        entries: entries,
+        notes: notes,
+        read_threads: folder_options.read_threads,
+    }
+}
+
+fn scan_dir(dir: &Path,
+            depth: u32,
+            options: &ScanOptions,
+            folder_options: &FolderOptions,
+            plots: &mut Vec<Plot>,
+            entries: &mut Vec<ScanEntry>) {
+    let dir_entries = match dir.read_dir() {
+        Ok(dir_entries) => dir_entries,
+        Err(e) => {
+            entries.push(ScanEntry {
+                path: dir.to_path_buf(),
+                status: ScanStatus::Invalid(format!("can't read folder: {}", e)),
+            });
+            return;
+        }
+    };
+
+    for dir_entry in dir_entries {
+        // DirEntry::path already includes the folder, relative or not
+        let (plot_path, is_dir) = match dir_entry {
+            Ok(dir_entry) => {
+                // symlinks aren't followed, so a link back up the tree can't loop forever
+                let is_dir = dir_entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+                (dir_entry.path(), is_dir)
+            }
+            Err(e) => {
+                entries.push(ScanEntry {
+                    path: dir.to_path_buf(),
+                    status: ScanStatus::Invalid(format!("can't read folder entry: {}", e)),
+                });
+                continue;
+            }
+        };
+        let hidden = plot_path.file_name()
+            .and_then(|name| name.to_str())
+            .map_or(false, |name| name.starts_with('.'));
+
+        let status = if let Some(pattern) = options.excluded_by(&plot_path) {
+            ScanStatus::Ignored(format!("excluded by {}", pattern))
+        } else if is_dir && depth < options.max_depth && !hidden {
+            scan_dir(&plot_path, depth + 1, options, folder_options, plots, entries);
+            continue;
+        } else if is_dir {
+            ScanStatus::Ignored(String::from("folder"))
+        } else if hidden {
+            ScanStatus::Ignored(String::from("hidden file"))
+        } else {
+            match Plot::from_path(&plot_path) {
+                Ok(Some(ref plot)) if !folder_options.accounts.is_empty() &&
+                                      !folder_options.accounts.contains(&plot.account_id) => {
+                    ScanStatus::Ignored(format!("account {} is not mined from this folder",
+                                                plot.account_id))
+                }
+                Ok(Some(mut plot)) => {
+                    match fs::metadata(&plot_path) {
+                        Ok(metadata) => {
+                            plot.file_id = Some((metadata.dev(), metadata.ino()));
+                            plot.check_geometry(metadata.len());
+                        }
+                        Err(e) => plot.health = PlotHealth::Unusable(format!("{}", e)),
+                    }
+                    match plot.health.clone() {
+                        PlotHealth::Healthy => {
+                            plots.push(plot);
+                            ScanStatus::Plot
+                        }
+                        PlotHealth::Degraded { reason, .. } => {
+                            plots.push(plot);
+                            ScanStatus::Degraded(reason)
+                        }
+                        PlotHealth::Unusable(reason) => ScanStatus::Invalid(reason),
+                    }
+                }
+                Ok(None) => ScanStatus::Ignored(String::from("not a plot file name")),
+                Err(reason) => ScanStatus::Invalid(reason),
+            }
+        };
+        entries.push(ScanEntry {
+            path: plot_path,
+            status: status,
+        });
+    }
+}
+
+/// Plots a single nonce in memory, the result holds its 4096 scoops in file order.
+pub fn plot_nonce(account_id: u64, nonce: u64) -> Vec<u8> {
+    // the plotter seeds with the big endian account id and nonce
+    let plot = generate_plot([account_id.to_be(), nonce.to_be()]);
+    plot[0..PLOT_SIZE].to_vec()
+}
+
 fn generate_plot(input: [u64; 2]) -> [u8; PLOT_SIZE + 16] {
     let mut output: [u8; PLOT_SIZE + 16] = [0; PLOT_SIZE + 16];
     unsafe {
@@ -108,7 +573,7 @@ fn generate_plot(input: [u64; 2]) -> [u8; PLOT_SIZE + 16] {
             sph_shabal::sph_shabal256(shabal_ctx_ptr,
                                       &output[i] as *const _ as *const libc::c_void,
                                       len);
-            sph_shabal::sph_shabal256_close(shabal_ctx_ptr,
+            sph_shabal::sph_shabal256_close(shabal_ctx_ptr,
                                 &mut output[i - HASH_SIZE] as *mut _ as *mut libc::c_void);
         }
         i -= HASH_SIZE;
@@ -127,7 +592,211 @@ fn generate_plot(input: [u64; 2]) -> [u8; PLOT_SIZE + 16] {
     for i in 0..PLOT_SIZE {
         output[i] ^= last_hash[i % 32];
     }
-
-    // println!("{:?}",output);
     return output;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use testing::TempDir;
+
+    #[test]
+    fn plot_nonce_matches_bundled_plot() {
+        // 15250033402865692318_8_8_2: nonces 8..16 in staggers of two
+        let mut data = Vec::new();
+        File::open("plots/15250033402865692318_8_8_2").unwrap().read_to_end(&mut data).unwrap();
+        for nonce in 8..16u64 {
+            let plotted = plot_nonce(15250033402865692318, nonce);
+            let stagger = (nonce - 8) as usize / 2;
+            let nonce_in_stagger = (nonce - 8) as usize % 2;
+            for &scoop in &[0usize, 1614, 3280, 4095] {
+                let offset = stagger * 2 * PLOT_SIZE + scoop * 2 * HASH_SIZE * 2 +
+                             nonce_in_stagger * HASH_SIZE * 2;
+                assert_eq!(&plotted[scoop * HASH_SIZE * 2..(scoop + 1) * HASH_SIZE * 2],
+                           &data[offset..offset + HASH_SIZE * 2],
+                           "nonce {} scoop {}",
+                           nonce,
+                           scoop);
+            }
+        }
+    }
+
+    #[test]
+    fn scoop_offset_follows_stagger_layout() {
+        let plot = Plot::from_path(Path::new("plots/15250033402865692318_8_8_2"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(plot.scoop_offset(8, 0), Some(0));
+        assert_eq!(plot.scoop_offset(9, 0), Some(64));
+        assert_eq!(plot.scoop_offset(9, 1), Some(2 * 64 + 64));
+        assert_eq!(plot.scoop_offset(10, 0), Some(2 * PLOT_SIZE as u64));
+        assert_eq!(plot.scoop_offset(7, 0), None);
+        assert_eq!(plot.scoop_offset(16, 0), None);
+    }
+
+    #[test]
+    fn scan_classifies_every_entry() {
+        let dir = TempDir::new("scan");
+        fs::create_dir(dir.join("subfolder")).unwrap();
+        for name in &["1_0_8_2", "README", "1_8_8_2.tmp", ".1_16_8_2", "1_99999999999999999999_8_2"] {
+            File::create(dir.join(name)).unwrap();
+        }
+        File::create(dir.join("1_0_8_2")).unwrap().set_len(8 * PLOT_SIZE as u64).unwrap();
+        let missing = dir.join("missing");
+
+        let plot_folders = get_plots(vec![dir.path().to_str().unwrap().to_owned(),
+                                          missing.to_str().unwrap().to_owned()],
+                                     &ScanOptions::default());
+
+        assert_eq!(plot_folders.plot_count(), 1);
+        let folder = &plot_folders.folders[0];
+        assert_eq!(folder.plots[0].path, dir.join("1_0_8_2"));
+        let status = |name: &str| {
+            folder.entries.iter().find(|entry| entry.path == dir.join(name)).unwrap().status.clone()
+        };
+        assert_eq!(status("1_0_8_2"), ScanStatus::Plot);
+        assert_eq!(status("README"),
+                   ScanStatus::Ignored(String::from("not a plot file name")));
+        assert_eq!(status("1_8_8_2.tmp"),
+                   ScanStatus::Ignored(String::from("not a plot file name")));
+        assert_eq!(status(".1_16_8_2"), ScanStatus::Ignored(String::from("hidden file")));
+        assert_eq!(status("subfolder"), ScanStatus::Ignored(String::from("folder")));
+        assert_eq!(status("1_99999999999999999999_8_2"),
+                   ScanStatus::Invalid(String::from("start nonce 99999999999999999999 does \
+                                                     not fit into 64 bits")));
+
+        let missing_folder = &plot_folders.folders[1];
+        assert!(missing_folder.plots.is_empty());
+        match missing_folder.entries[0].status {
+            ScanStatus::Invalid(ref reason) => assert!(reason.starts_with("can't read folder")),
+            ref other => panic!("missing folder scanned as {:?}", other),
+        }
+    }
+
+    #[test]
+    fn folder_options_filter_plots() {
+        let mut options = ScanOptions::default();
+        options.folders.insert(String::from("plots"),
+                               FolderOptions {
+                                   read_backend: Some(ReadBackend::Pread),
+                                   priority: 3,
+                                   rate_limit: Some(10),
+                                   accounts: vec![1],
+                                   ..FolderOptions::default()
+                               });
+        let plot_folders = get_plots(vec![String::from("plots")], &options);
+        let folder = &plot_folders.folders[0];
+        assert!(folder.plots.is_empty());
+        assert_eq!(folder.entries[0].status,
+                   ScanStatus::Ignored(String::from("account 15250033402865692318 is not mined \
+                                                     from this folder")));
+
+        options.folders.get_mut("plots").unwrap().accounts = vec![15250033402865692318];
+        let plot_folders = get_plots(vec![String::from("plots")], &options);
+        let plots = &plot_folders.folders[0].plots;
+        assert_eq!(plots.len(), 2);
+        assert!(plots.iter()
+            .all(|plot| plot.read_backend == ReadBackend::Pread && plot.priority == 3 &&
+                        plot.throttle.is_some()));
+
+        options.folders.get_mut("plots").unwrap().enabled = false;
+        let plot_folders = get_plots(vec![String::from("plots")], &options);
+        assert_eq!(plot_folders.plot_count(), 0);
+        assert_eq!(plot_folders.folders[0].notes, vec!["disabled, not scanned"]);
+    }
+
+    #[test]
+    fn no_usable_plots_is_an_error() {
+        let dir = TempDir::new("missing");
+        let missing = dir.join("missing");
+        let options = ScanOptions::default();
+        assert!(get_usable_plots(vec![missing.to_str().unwrap().to_owned()], &options).is_err());
+        assert!(get_usable_plots(vec![String::from("plots")], &options).is_ok());
+    }
+
+    #[test]
+    fn geometry_checks() {
+        let plot = |name: &str, file_len: u64| {
+            let mut plot = Plot::from_path(Path::new(name)).unwrap().unwrap();
+            plot.check_geometry(file_len);
+            plot
+        };
+        let nonces = |n: u64| n * PLOT_SIZE as u64;
+
+        let healthy = plot("1_0_8_2", nonces(8));
+        assert_eq!(healthy.health, PlotHealth::Healthy);
+        assert_eq!(healthy.usable_nonces(), 8);
+
+        let truncated = plot("1_0_8_2", nonces(5));
+        assert_eq!(truncated.usable_nonces(), 4);
+        match truncated.health {
+            PlotHealth::Degraded { ref reason, .. } => assert!(reason.contains("mining 4 of 8")),
+            ref other => panic!("{:?}", other),
+        }
+
+        let ragged = plot("1_0_9_2", nonces(9));
+        assert_eq!(ragged.usable_nonces(), 8);
+
+        assert_eq!(plot("1_0_8_2", nonces(9)).usable_nonces(), 8);
+        assert_eq!(plot("1_0_8_2", nonces(1)).health,
+                   PlotHealth::Unusable(format!("file is {} bytes, expected {}",
+                                                nonces(1),
+                                                nonces(8))));
+        assert_eq!(plot("1_0_8_0", nonces(8)).health,
+                   PlotHealth::Unusable(String::from("stagger size is 0")));
+        assert_eq!(plot("1_0_0_2", 0).health,
+                   PlotHealth::Unusable(String::from("nonce count is 0")));
+        assert_eq!(plot("1_0_18446744073709551615_1", 0).health,
+                   PlotHealth::Unusable(String::from("nonce count is too large")));
+    }
+
+    #[test]
+    fn globs_and_recursion() {
+        let dir = TempDir::new("glob");
+        for (folder, name) in vec![("disk1/plots", "1_0_8_2"),
+                                   ("disk2/plots", "1_8_8_2"),
+                                   ("disk2/plots/old", "1_16_8_2"),
+                                   ("disk2/plots/old/deeper", "1_24_8_2"),
+                                   ("disk2/plots/trash", "1_32_8_2")] {
+            fs::create_dir_all(dir.join(folder)).unwrap();
+            File::create(dir.join(folder).join(name))
+                .unwrap()
+                .set_len(8 * PLOT_SIZE as u64)
+                .unwrap();
+        }
+        let options = ScanOptions {
+            max_depth: 1,
+            exclude: vec![glob::Pattern::new("*/trash").unwrap()],
+            ..ScanOptions::default()
+        };
+
+        let plot_folders = get_plots(vec![format!("{}/disk*/plots", dir.path().display()),
+                                          format!("{}/nodisk*/plots", dir.path().display())],
+                                     &options);
+
+        let names = |folder: &PlotFolder| -> Vec<u64> {
+            folder.plots.iter().map(|plot| plot.start_nonce).collect()
+        };
+        assert_eq!(plot_folders.folders.len(), 3);
+        assert_eq!(plot_folders.folders[0].path, dir.join("disk1/plots"));
+        assert_eq!(names(&plot_folders.folders[0]), vec![0]);
+        assert_eq!(names(&plot_folders.folders[1]), vec![8, 16]);
+        let status = |path: &str| {
+            plot_folders.folders[1]
+                .entries
+                .iter()
+                .find(|entry| entry.path == dir.join(path))
+                .unwrap()
+                .status
+                .clone()
+        };
+        assert_eq!(status("disk2/plots/old/deeper"),
+                   ScanStatus::Ignored(String::from("folder")));
+        assert_eq!(status("disk2/plots/trash"),
+                   ScanStatus::Ignored(String::from("excluded by */trash")));
+        assert_eq!(plot_folders.folders[2].entries[0].status,
+                   ScanStatus::Invalid(String::from("pattern matches no folder")));
+    }
+}
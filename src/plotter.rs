@@ -0,0 +1,122 @@
+use cli::Args;
+use constants::*;
+use plots;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The largest stagger picked when none is given, 2 GiB of memory.
+const MAX_DEFAULT_STAGGER: u64 = 8192;
+
+/// `plot <folder> --account <id> --start <nonce> --nonces <count> [--stagger <size>]`
+///
+/// Writes a PoC1 plot file. A whole stagger is built in memory before it is written, so the
+/// stagger size decides how much memory plotting takes: 256 KiB per nonce.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse_plain(args, &["account", "start", "nonces", "stagger"]));
+    if args.positional.len() != 1 {
+        return Err(String::from("expected the <folder> to plot into"));
+    }
+    let account_id = try!(args.required::<u64>("account"));
+    let start_nonce = try!(args.required::<u64>("start"));
+    let nonce_count = try!(args.required::<u64>("nonces"));
+    let stagger_size = try!(args.option("stagger", default_stagger(nonce_count)));
+
+    let path = try!(write_plot(Path::new(&args.positional[0]),
+                               account_id,
+                               start_nonce,
+                               nonce_count,
+                               stagger_size));
+    println!("wrote {:?}", path);
+    Ok(())
+}
+
+/// The largest stagger up to `MAX_DEFAULT_STAGGER` that `nonce_count` splits into evenly.
+fn default_stagger(nonce_count: u64) -> u64 {
+    (1..MAX_DEFAULT_STAGGER + 1)
+        .rev()
+        .find(|stagger| nonce_count % stagger == 0)
+        .unwrap_or(1)
+}
+
+/// Plots `nonce_count` nonces starting at `start_nonce` into `folder`, under the name the
+/// scanner expects. The file only gets that name once it is complete.
+pub fn write_plot(folder: &Path,
+                  account_id: u64,
+                  start_nonce: u64,
+                  nonce_count: u64,
+                  stagger_size: u64)
+                  -> Result<PathBuf, String> {
+    if nonce_count == 0 || stagger_size == 0 || nonce_count % stagger_size != 0 {
+        return Err(format!("{} nonces can't be split into staggers of {}",
+                           nonce_count,
+                           stagger_size));
+    }
+    let path = folder.join(format!("{}_{}_{}_{}",
+                                   account_id,
+                                   start_nonce,
+                                   nonce_count,
+                                   stagger_size));
+    if path.exists() {
+        return Err(format!("{:?} exists already", path));
+    }
+    let partial_path = path.with_extension("plotting");
+    let mut file = try!(File::create(&partial_path)
+        .map_err(|e| format!("creating {:?}: {}", partial_path, e)));
+
+    let scoop_size = HASH_SIZE * 2;
+    let stagger_size = stagger_size as usize;
+    let mut stagger_data = vec![0u8; stagger_size * PLOT_SIZE];
+    for stagger in 0..nonce_count / stagger_size as u64 {
+        for nonce_in_stagger in 0..stagger_size {
+            let nonce = start_nonce + stagger * stagger_size as u64 + nonce_in_stagger as u64;
+            let nonce_data = plots::plot_nonce(account_id, nonce);
+            // scoop after scoop, with the same scoop of every nonce of the stagger together
+            for (scoop, data) in nonce_data.chunks(scoop_size).enumerate() {
+                let offset = (scoop * stagger_size + nonce_in_stagger) * scoop_size;
+                stagger_data[offset..offset + scoop_size].copy_from_slice(data);
+            }
+        }
+        try!(file.write_all(&stagger_data)
+            .map_err(|e| format!("writing {:?}: {}", partial_path, e)));
+        println!("plotted {} of {} nonces",
+                 (stagger + 1) * stagger_size as u64,
+                 nonce_count);
+    }
+    try!(file.sync_all()
+        .and_then(|_| fs::rename(&partial_path, &path))
+        .map_err(|e| format!("finishing {:?}: {}", path, e)));
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use testing::TempDir;
+
+    #[test]
+    fn writes_the_bundled_plot_again() {
+        let dir = TempDir::new("plotter");
+
+        let path = write_plot(dir.path(), 15250033402865692318, 0, 8, 2).unwrap();
+        assert_eq!(path, dir.join("15250033402865692318_0_8_2"));
+        assert!(write_plot(dir.path(), 15250033402865692318, 0, 8, 2).is_err());
+        assert!(write_plot(dir.path(), 15250033402865692318, 0, 8, 3).is_err());
+
+        let mut plotted = Vec::new();
+        File::open(&path).unwrap().read_to_end(&mut plotted).unwrap();
+        let mut bundled = Vec::new();
+        File::open("plots/15250033402865692318_0_8_2").unwrap().read_to_end(&mut bundled).unwrap();
+        assert!(plotted == bundled, "plot differs from the bundled one");
+    }
+
+    #[test]
+    fn bounds_the_default_stagger() {
+        assert_eq!(default_stagger(8), 8);
+        assert_eq!(default_stagger(4000000), 8000);
+        assert_eq!(default_stagger(8192 * 3), 8192);
+        assert_eq!(default_stagger(10007), 1);
+    }
+}
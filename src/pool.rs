@@ -6,13 +6,19 @@ use rustc_serialize::hex::FromHexError;
 use hyper::Url;
 use hyper::client::Client;
 use hyper::error::Error as HyperError;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::mpsc::{Sender, SendError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::io::{Read, Error as IoError};
 use std::ops::Deref;
+use capture::{self, Capture};
 use miner;
+use notify::Progress;
+
+/// How long the pool can be unreachable before a reloaded configuration takes over without
+/// waiting for the next block, about the time of one block.
+const UNREACHABLE_SWITCH: Duration = Duration::from_secs(240);
 
 #[derive(Debug, Clone)]
 pub struct MiningInfo {
@@ -56,6 +62,8 @@ pub enum Error {
     Parse(json::DecoderError),
     Subscriber(SendError<miner::MinerWork>),
     Url,
+    /// the miner is shutting down and doesn't submit anymore
+    Stopped,
 }
 
 impl From<HyperError> for Error {
@@ -88,46 +96,162 @@ impl From<SendError<miner::MinerWork>> for Error {
     }
 }
 
+/// What the pool is asked and told, a reloaded configuration can change it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolSettings {
+    pub url: Url,
+    /// seconds, deadlines above it aren't submitted even if the pool would take them
+    pub max_deadline: Option<u64>,
+}
+
 #[derive(Clone)]
 pub struct Pool {
-    url: Url,
+    settings: Arc<Mutex<PoolSettings>>,
+    /// settings waiting for the next block
+    pending: Arc<Mutex<Option<PoolSettings>>>,
+    /// when the first of the requests for mining info that failed in a row was sent
+    unreachable_since: Arc<Mutex<Option<Instant>>>,
     mining_info: Arc<Mutex<Option<MiningInfo>>>,
     subscribers: Arc<Mutex<Vec<Sender<miner::MinerWork>>>>,
     client: Arc<Mutex<Client>>,
     started: Arc<Mutex<bool>>,
+    /// set by `stop`, the condvar wakes the polling thread up
+    stopping: Arc<(Mutex<bool>, Condvar)>,
+    /// submissions waiting for the pool to answer
+    in_flight: Arc<(Mutex<usize>, Condvar)>,
+    capture: Option<Capture>,
+    progress: Option<Progress>,
 }
 
 impl Pool {
+    #[cfg(test)]
     pub fn from_url(url: Url) -> Pool {
-        Pool {
+        Pool::with_settings(PoolSettings {
             url: url,
+            max_deadline: None,
+        })
+    }
+
+    pub fn with_settings(settings: PoolSettings) -> Pool {
+        Pool {
+            settings: Arc::new(Mutex::new(settings)),
+            pending: Arc::new(Mutex::new(None)),
+            unreachable_since: Arc::new(Mutex::new(None)),
             mining_info: Arc::new(Mutex::new(None)),
             subscribers: Arc::new(Mutex::new(Vec::new())),
             client: Arc::new(Mutex::new(Client::new())),
             started: Arc::new(Mutex::new(false)),
+            stopping: Arc::new((Mutex::new(false), Condvar::new())),
+            in_flight: Arc::new((Mutex::new(0), Condvar::new())),
+            capture: None,
+            progress: None,
         }
+    }
 
+    /// Records every request to the pool and its raw response into `capture`.
+    pub fn with_capture(mut self, capture: Capture) -> Pool {
+        self.capture = Some(capture);
+        self
     }
 
-    pub fn start(&self) {
+    /// Reports polling, new blocks and the progress of the miner threads to `progress`.
+    pub fn with_progress(mut self, progress: Progress) -> Pool {
+        self.progress = Some(progress);
+        self
+    }
+
+    pub fn settings(&self) -> PoolSettings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Switches to `settings` once the current pool announces the next block, so the round in
+    /// progress isn't lost. A pool that has been unreachable for about a block is left right away.
+    pub fn reconfigure(&self, settings: PoolSettings) {
+        *self.pending.lock().unwrap() = Some(settings);
+    }
+
+    /// Takes over the pending settings, returns whether the url changed.
+    fn apply_pending(&self) -> bool {
+        let pending = match self.pending.lock().unwrap().take() {
+            Some(pending) => pending,
+            None => return false,
+        };
+        let mut settings = self.settings.lock().unwrap();
+        let url_changed = settings.url != pending.url;
+        if url_changed {
+            info!("switching to pool {}", capture::redact(&pending.url));
+        }
+        *settings = pending;
+        url_changed
+    }
+
+    pub fn progress(&self) -> Option<&Progress> {
+        self.progress.as_ref()
+    }
+
+    /// Starts polling the pool for mining info, returns the polling thread unless it was started
+    /// before. The thread finishes after `stop`, otherwise only by panicking.
+    pub fn start(&self) -> Option<thread::JoinHandle<()>> {
         let mut started_mutex_guard = self.started.lock().unwrap();
+        let mut handle = None;
         if *started_mutex_guard == false {
             let pool_ref = self.clone();
-            thread::spawn(move || {
-                loop {
+            handle = Some(thread::spawn(move || {
+                while !pool_ref.stopped() {
                     if let Err(e) = pool_ref.refresh() {
-                        println!("refresh pool: {:?}", e);
+                        warn!("refresh pool: {:?}", e);
+                    }
+                    // a failed request still shows the loop isn't stuck, a hanging one doesn't
+                    if let Some(ref progress) = pool_ref.progress {
+                        progress.pool_alive();
+                    }
+                    let &(ref stopping, ref stopped) = &*pool_ref.stopping;
+                    let stopping = stopping.lock().unwrap();
+                    if !*stopping {
+                        let _ = stopped.wait_timeout(stopping, Duration::from_secs(5)).unwrap();
                     }
-                    thread::sleep(Duration::from_secs(5));
                 }
-            });
+            }));
         }
         *started_mutex_guard = true;
+        handle
+    }
+
+    /// Stops polling the pool and refuses new submissions, then waits up to `timeout` for the
+    /// submissions in flight. Returns false when some were still waiting for the pool.
+    ///
+    /// The miner threads get no more work, they finish once their round is over.
+    pub fn stop(&self, timeout: Duration) -> bool {
+        {
+            let &(ref stopping, ref stopped) = &*self.stopping;
+            *stopping.lock().unwrap() = true;
+            stopped.notify_all();
+        }
+        self.subscribers.lock().unwrap().clear();
+        let deadline = Instant::now() + timeout;
+        let &(ref count, ref finished) = &*self.in_flight;
+        let mut in_flight = count.lock().unwrap();
+        while *in_flight > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                warn!("{} submissions still waiting for the pool", *in_flight);
+                break;
+            }
+            in_flight = finished.wait_timeout(in_flight, deadline - now).unwrap().0;
+        }
+        let all_finished = *in_flight == 0;
+        if let Some(ref capture) = self.capture {
+            capture.flush();
+        }
+        all_finished
+    }
+
+    fn stopped(&self) -> bool {
+        *self.stopping.0.lock().unwrap()
     }
 
     fn query_pool(&self) -> Result<MiningInfo, Error> {
-        let ref http_client = self.client;
-        let mut query_url = self.url.clone();
+        let mut query_url = self.settings().url;
         match query_url.path_segments_mut() {
             Ok(mut path_segments) => {
                 path_segments.pop_if_empty().push("burst");
@@ -135,18 +259,41 @@ impl Pool {
             Err(_) => return Err(Error::Url),
         };
         query_url.query_pairs_mut().append_pair("requestType", "getMiningInfo");
-        let mut response = String::new();
-        {
-            let client_unwrapped = http_client.lock().unwrap();
-            let mut res = try!(client_unwrapped.get(query_url).send());
-            try!(res.read_to_string(&mut response));
-        }
+        let response = try!(self.get("getMiningInfo", query_url));
         let mining_info = try!(json::decode(response.as_str()));
         Ok(mining_info)
     }
 
+    fn get(&self, request_type: &str, query_url: Url) -> Result<String, Error> {
+        let result = {
+            let client_unwrapped = self.client.lock().unwrap();
+            client_unwrapped.get(query_url.clone())
+                .send()
+                .map_err(Error::from)
+                .and_then(|mut res| {
+                    let mut response = String::new();
+                    try!(res.read_to_string(&mut response));
+                    Ok((res.status.to_u16(), response))
+                })
+        };
+        if let Some(ref capture) = self.capture {
+            capture.record(request_type, &query_url, &result);
+        }
+        result.map(|(_, response)| response)
+    }
+
     fn refresh(&self) -> Result<(), Error> {
-        let new_mining_info = try!(self.query_pool());
+        let requested = Instant::now();
+        let mut new_mining_info = match self.query_pool() {
+            Ok(mining_info) => mining_info,
+            Err(e) => {
+                if self.unreachable(requested) {
+                    self.apply_pending();
+                }
+                return Err(e);
+            }
+        };
+        *self.unreachable_since.lock().unwrap() = None;
         match self.mining_info.lock() {
             Err(_) => {
                 panic!("Mutex holding the pool state was poisoned. The main thread may have \
@@ -159,7 +306,14 @@ impl Pool {
                         return Ok(()); //no update
                     }
                 }
-                println!("new mining info from pool: {:?}", new_mining_info);
+                // a new block is where a reloaded configuration takes over
+                if self.apply_pending() {
+                    new_mining_info = try!(self.query_pool());
+                }
+                info!("new mining info from pool: {:?}", new_mining_info);
+                if let Some(ref progress) = self.progress {
+                    progress.new_block(new_mining_info.height);
+                }
                 *mining_info_guard = Some(new_mining_info.clone());
                 try!(self.notify_subscribers(new_mining_info));
             }
@@ -167,15 +321,29 @@ impl Pool {
         Ok(())
     }
 
+    /// Records a failed request for mining info sent at `requested`, returns whether the pool
+    /// has been unreachable for so long it won't announce the next block in time anymore.
+    fn unreachable(&self, requested: Instant) -> bool {
+        let mut unreachable_since = self.unreachable_since.lock().unwrap();
+        let since = *unreachable_since.get_or_insert(requested);
+        requested - since >= UNREACHABLE_SWITCH
+    }
+
     pub fn add_subscriber(&self, subscriber: Sender<miner::MinerWork>) -> Result<(), Error> {
         let mut subs = self.subscribers.lock().unwrap();
-        subs.push(subscriber);
+        // a rescan can still start miner threads while the miner shuts down
+        if !self.stopped() {
+            subs.push(subscriber);
+        }
         Ok(())
     }
 
     fn notify_subscribers(&self, mining_info: MiningInfo) -> Result<(), Error> {
-        let miner_work = try!(miner::MinerWork::from_mining_info(mining_info));
-        println!("new scoop num: {}", miner_work.scoop_num);
+        let mut miner_work = try!(miner::MinerWork::from_mining_info(mining_info));
+        if let Some(max_deadline) = self.settings().max_deadline {
+            miner_work.target_deadline = miner_work.target_deadline.min(max_deadline);
+        }
+        debug!("new scoop num: {}", miner_work.scoop_num);
         for sender in self.subscribers.lock().unwrap().deref() {
             try!(sender.send(miner_work.clone()))
         }
@@ -183,7 +351,8 @@ impl Pool {
     }
 
     pub fn submit_hash(&self, nonce: u64, account_id: u64) -> Result<String, Error> {
-        let mut query_url = self.url.clone();
+        let settings = self.settings();
+        let mut query_url = settings.url;
         match query_url.path_segments_mut() {
             Ok(mut path_segments) => {
                 path_segments.pop_if_empty().push("burst");
@@ -195,13 +364,121 @@ impl Pool {
             .append_pair("accountId", &account_id.to_string())
             .append_pair("nonce", &nonce.to_string())
             .append_pair("secretPhrase", "cryptoport");
-        println!("{}", query_url);
-        let mut response = String::new();
-        let ref http_client = self.client.lock().unwrap();
-        let mut res = try!(http_client.get(query_url).send());
-
+        {
+            // checked under the lock, so `stop` either waits for this submission or it is refused
+            let mut in_flight = self.in_flight.0.lock().unwrap();
+            if self.stopped() {
+                return Err(Error::Stopped);
+            }
+            *in_flight += 1;
+        }
+        debug!("{}", capture::redact(&query_url));
         // assert_eq!(res.status, hyper::Ok);
-        res.read_to_string(&mut response).unwrap();
-        return Ok(response);
+        let result = self.get("submitNonce", query_url);
+        let &(ref count, ref finished) = &*self.in_flight;
+        *count.lock().unwrap() -= 1;
+        finished.notify_all();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capture::{self, Capture};
+    use mock_pool::{MockPool, mining_info_json};
+    use std::net::TcpListener;
+    use std::sync::mpsc::channel;
+    use testing::TempDir;
+
+    #[test]
+    fn captured_traffic_replays_through_mock_pool() {
+        let sig = "2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1";
+        let live = MockPool::start(vec![mining_info_json(sig, 18325193796, 480000, 86400),
+                                        String::from("<html>pool overloaded</html>")]);
+        let dir = TempDir::new("capture");
+        let capture_path = dir.join("pool.capture");
+
+        let pool = Pool::from_url(live.url()).with_capture(Capture::open(&capture_path).unwrap());
+        assert_eq!(pool.query_pool().unwrap().height, 480000);
+        assert_eq!(pool.submit_hash(6, 15250033402865692318).unwrap(),
+                   "{\"result\":\"success\"}");
+        assert!(pool.query_pool().is_err());
+
+        let entries = capture::read(&capture_path).unwrap();
+        assert_eq!(entries.iter().map(|e| e.request_type.as_str()).collect::<Vec<_>>(),
+                   vec!["getMiningInfo", "submitNonce", "getMiningInfo"]);
+        assert!(entries[1].request.contains("secretPhrase=REDACTED"));
+        assert!(!entries[1].request.contains("cryptoport"));
+        assert_eq!(entries[2].status, Some(200));
+
+        let replayed = MockPool::from_capture("127.0.0.1:0", &capture_path).unwrap();
+        let pool = Pool::from_url(replayed.url());
+        assert_eq!(pool.query_pool().unwrap().generation_signature, sig);
+        assert_eq!(pool.submit_hash(6, 15250033402865692318).unwrap(),
+                   "{\"result\":\"success\"}");
+        match pool.query_pool() {
+            Err(Error::Parse(_)) => {}
+            other => panic!("expected the captured garbage again, got {:?}", other),
+        }
+        assert_eq!(replayed.submissions()[0].nonce, 6);
+    }
+
+    #[test]
+    fn refuses_submissions_after_stop() {
+        let sig = "2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1";
+        let mock = MockPool::start(vec![mining_info_json(sig, 18325193796, 480000, 86400)]);
+        let pool = Pool::from_url(mock.url());
+        assert!(pool.stop(Duration::from_secs(1)));
+        match pool.submit_hash(6, 15250033402865692318) {
+            Err(Error::Stopped) => {}
+            other => panic!("expected the submission to be refused, got {:?}", other),
+        }
+        assert!(mock.submissions().is_empty());
+    }
+
+    #[test]
+    fn applies_new_settings_at_the_next_block() {
+        let sig = "2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1";
+        let next_sig = "3d2f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1";
+        let old = MockPool::start(vec![mining_info_json(sig, 18325193796, 480000, 86400),
+                                       mining_info_json(next_sig, 18325193796, 480001, 86400)]);
+        let new = MockPool::start(vec![mining_info_json(next_sig, 18325193796, 480001, 86400)]);
+        let pool = Pool::from_url(old.url());
+        let (work, work_recv) = channel();
+        pool.add_subscriber(work).unwrap();
+        pool.refresh().unwrap();
+        assert_eq!(work_recv.try_recv().unwrap().target_deadline, 86400);
+
+        let settings = PoolSettings {
+            url: new.url(),
+            max_deadline: Some(1000),
+        };
+        pool.reconfigure(settings.clone());
+        assert_eq!(pool.settings().url, old.url());
+        pool.refresh().unwrap();
+        assert_eq!(pool.settings(), settings);
+        let next_work = work_recv.try_recv().unwrap();
+        assert_eq!((next_work.height, next_work.target_deadline), (480001, 1000));
+    }
+
+    #[test]
+    fn leaves_an_unreachable_pool_only_after_about_a_block() {
+        let gone = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            Url::parse(&format!("http://{}", listener.local_addr().unwrap())).unwrap()
+        };
+        let pool = Pool::from_url(gone.clone());
+        pool.reconfigure(PoolSettings {
+            url: Url::parse("http://127.0.0.1:8124").unwrap(),
+            max_deadline: None,
+        });
+
+        // one failed request doesn't cost the round in progress
+        assert!(pool.refresh().is_err());
+        assert_eq!(pool.settings().url, gone);
+        let failed = Instant::now();
+        assert!(!pool.unreachable(failed + UNREACHABLE_SWITCH / 2));
+        assert!(pool.unreachable(failed + UNREACHABLE_SWITCH));
     }
 }
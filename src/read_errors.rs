@@ -0,0 +1,170 @@
+use devices;
+use plots::Plot;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorCount {
+    /// reads that failed and were tried again
+    pub retried: u64,
+    /// staggers left out of a round because every try failed
+    pub skipped_staggers: u64,
+}
+
+impl ErrorCount {
+    fn add(&mut self, other: &ErrorCount) {
+        self.retried += other.retried;
+        self.skipped_staggers += other.skipped_staggers;
+    }
+}
+
+struct PlotErrors {
+    device: String,
+    count: ErrorCount,
+}
+
+struct State {
+    plots: BTreeMap<PathBuf, PlotErrors>,
+    quarantined: BTreeSet<PathBuf>,
+}
+
+/// Read errors of all miner threads, and the plots that failed so often they aren't mined
+/// anymore until the miner restarts.
+#[derive(Clone)]
+pub struct ReadErrors {
+    /// how often a failed read is tried again before its stagger is skipped
+    pub retries: u32,
+    /// skipped staggers after which a plot is quarantined
+    pub quarantine_after: u64,
+    state: Arc<Mutex<State>>,
+}
+
+impl ReadErrors {
+    pub fn new(retries: u32, quarantine_after: u64) -> ReadErrors {
+        ReadErrors {
+            retries: retries,
+            quarantine_after: quarantine_after,
+            state: Arc::new(Mutex::new(State {
+                plots: BTreeMap::new(),
+                quarantined: BTreeSet::new(),
+            })),
+        }
+    }
+
+    pub fn record_retry(&self, plot: &Plot) {
+        let mut state = self.state.lock().unwrap();
+        entry(&mut state, plot).count.retried += 1;
+    }
+
+    /// Counts a skipped stagger, returns true when that put the plot into quarantine.
+    pub fn record_skipped(&self, plot: &Plot) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let skipped = {
+            let errors = entry(&mut state, plot);
+            errors.count.skipped_staggers += 1;
+            errors.count.skipped_staggers
+        };
+        if skipped >= self.quarantine_after && !state.quarantined.contains(&plot.path) {
+            state.quarantined.insert(plot.path.clone());
+            return true;
+        }
+        false
+    }
+
+    /// Quarantines a plot right away, e.g. because reading it crashed a miner thread.
+    pub fn quarantine(&self, path: &Path) {
+        self.state.lock().unwrap().quarantined.insert(path.to_path_buf());
+    }
+
+    pub fn is_quarantined(&self, path: &Path) -> bool {
+        self.state.lock().unwrap().quarantined.contains(path)
+    }
+
+    pub fn plot_count(&self, path: &Path) -> ErrorCount {
+        let state = self.state.lock().unwrap();
+        state.plots.get(path).map(|errors| errors.count.clone()).unwrap_or_default()
+    }
+
+    pub fn device_counts(&self) -> BTreeMap<String, ErrorCount> {
+        let state = self.state.lock().unwrap();
+        let mut devices: BTreeMap<String, ErrorCount> = BTreeMap::new();
+        for errors in state.plots.values() {
+            devices.entry(errors.device.clone())
+                .or_insert_with(Default::default)
+                .add(&errors.count);
+        }
+        devices
+    }
+
+    /// The error counts of every plot and disk that had read errors, one line each.
+    pub fn report(&self) -> Vec<String> {
+        let mut lines = Vec::new();
+        {
+            let state = self.state.lock().unwrap();
+            for (path, errors) in &state.plots {
+                lines.push(format!("read errors of {:?}: {} retried, {} staggers skipped{}",
+                                   path,
+                                   errors.count.retried,
+                                   errors.count.skipped_staggers,
+                                   if state.quarantined.contains(path) {
+                                       ", quarantined"
+                                   } else {
+                                       ""
+                                   }));
+            }
+        }
+        for (device, count) in self.device_counts() {
+            lines.push(format!("read errors of device {}: {} retried, {} staggers skipped",
+                               device,
+                               count.retried,
+                               count.skipped_staggers));
+        }
+        lines
+    }
+}
+
+fn entry<'a>(state: &'a mut State, plot: &Plot) -> &'a mut PlotErrors {
+    state.plots.entry(plot.path.clone()).or_insert_with(|| {
+        PlotErrors {
+            device: match plot.file_id {
+                Some((dev, _)) => devices::device_name(dev),
+                None => String::from("unknown"),
+            },
+            count: ErrorCount::default(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots;
+
+    #[test]
+    fn quarantines_after_enough_skipped_staggers() {
+        let plot = plots::get_plots(vec![String::from("plots")], &Default::default())
+            .folders
+            .remove(0)
+            .plots
+            .remove(0);
+        let errors = ReadErrors::new(2, 3);
+
+        errors.record_retry(&plot);
+        errors.record_retry(&plot);
+        assert!(!errors.record_skipped(&plot));
+        assert!(!errors.record_skipped(&plot));
+        assert!(!errors.is_quarantined(&plot.path));
+        assert!(errors.record_skipped(&plot));
+        assert!(errors.is_quarantined(&plot.path));
+        // only the skip that crossed the limit reports the quarantine
+        assert!(!errors.record_skipped(&plot));
+
+        let count = ErrorCount {
+            retried: 2,
+            skipped_staggers: 4,
+        };
+        assert_eq!(errors.plot_count(&plot.path), count);
+        assert_eq!(errors.device_counts().values().cloned().collect::<Vec<_>>(), vec![count]);
+    }
+}
@@ -0,0 +1,390 @@
+extern crate memmap;
+
+use constants::*;
+use guard;
+use libc;
+use plots::Plot;
+use self::memmap::{Mmap, Protection};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use uring::{ReadStaggers, StaggerReads};
+
+/// Direct I/O needs buffers, offsets and lengths aligned to the logical block size of the disk,
+/// 4096 covers every disk in use.
+pub const DIRECT_ALIGN: usize = 4096;
+
+/// How a plot file is read.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadBackend {
+    /// through a memory mapping and the page cache
+    Mmap,
+    /// with `O_DIRECT` into our own buffer, past the page cache
+    Direct,
+    /// with plain `pread` into our own buffer, through the page cache
+    Pread,
+    /// like `Direct`, with many reads across plots in flight at once through io_uring
+    Uring,
+}
+
+impl ReadBackend {
+    pub fn parse(name: &str) -> Result<ReadBackend, String> {
+        match name {
+            "mmap" => Ok(ReadBackend::Mmap),
+            "direct" => Ok(ReadBackend::Direct),
+            "pread" => Ok(ReadBackend::Pread),
+            "io_uring" => Ok(ReadBackend::Uring),
+            _ => {
+                Err(format!("unknown read backend {}, expected mmap, direct, pread or io_uring",
+                            name))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadError {
+    /// the file ends before the stagger, trying again won't help
+    PastEnd,
+    Failed(String),
+}
+
+impl fmt::Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReadError::PastEnd => write!(f, "past end of file"),
+            ReadError::Failed(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// Where a round gets its scoops from, the plot files themselves unless a test stands in.
+pub trait ScoopSource {
+    /// Opens a plot for one round with the backend its folder asks for.
+    fn open(&self, plot: &Plot) -> Result<Box<ReadScoops>, String>;
+
+    /// Starts reading the scoops of every stagger of `plots` through io_uring.
+    fn stagger_reads<'a>(&self,
+                         plots: &'a [Plot],
+                         scoop_num: u16,
+                         retries: u32)
+                         -> io::Result<Box<ReadStaggers + 'a>>;
+}
+
+/// Reads the plot files on disk.
+pub struct PlotFiles;
+
+impl ScoopSource for PlotFiles {
+    fn open(&self, plot: &Plot) -> Result<Box<ReadScoops>, String> {
+        PlotReader::open(plot).map(|reader| Box::new(reader) as Box<ReadScoops>)
+    }
+
+    fn stagger_reads<'a>(&self,
+                         plots: &'a [Plot],
+                         scoop_num: u16,
+                         retries: u32)
+                         -> io::Result<Box<ReadStaggers + 'a>> {
+        StaggerReads::start(plots, scoop_num, retries)
+            .map(|reads| Box::new(reads) as Box<ReadStaggers + 'a>)
+    }
+}
+
+/// One plot, read a stagger at a time.
+pub trait ReadScoops {
+    /// The scoops of all nonces in a stagger.
+    fn scoops(&mut self, stagger: u64, scoop_num: u16) -> Result<&[u8], ReadError>;
+
+    /// Asks the kernel to start reading the scoops of a stagger that will be needed soon.
+    fn prefetch(&self, stagger: u64, scoop_num: u16);
+}
+
+/// A plot file opened for one round with the backend its folder asks for.
+pub enum PlotReader {
+    Mapped(MappedPlot),
+    Direct(DirectPlot),
+}
+
+impl PlotReader {
+    pub fn open(plot: &Plot) -> Result<PlotReader, String> {
+        match plot.read_backend {
+            ReadBackend::Mmap => MappedPlot::open(plot).map(PlotReader::Mapped),
+            ReadBackend::Direct => DirectPlot::open(plot).map(PlotReader::Direct),
+            ReadBackend::Pread => DirectPlot::open_cached(plot).map(PlotReader::Direct),
+            // io_uring plots only come here when the ring couldn't be set up
+            ReadBackend::Uring => {
+                match DirectPlot::open(plot) {
+                    Ok(direct) => Ok(PlotReader::Direct(direct)),
+                    Err(_) => MappedPlot::open(plot).map(PlotReader::Mapped),
+                }
+            }
+        }
+    }
+}
+
+impl ReadScoops for PlotReader {
+    fn scoops(&mut self, stagger: u64, scoop_num: u16) -> Result<&[u8], ReadError> {
+        match *self {
+            PlotReader::Mapped(ref mut mapped) => mapped.scoops(stagger, scoop_num),
+            PlotReader::Direct(ref mut direct) => direct.scoops(stagger, scoop_num),
+        }
+    }
+
+    fn prefetch(&self, stagger: u64, scoop_num: u16) {
+        match *self {
+            PlotReader::Mapped(ref mapped) => mapped.prefetch(stagger, scoop_num),
+            // direct reads bypass the cache any readahead would go to
+            PlotReader::Direct(_) => {}
+        }
+    }
+}
+
+/// Whether the file system the file is on accepts `O_DIRECT`, tmpfs and some FUSE file systems
+/// don't.
+pub fn supports_direct(path: &Path) -> bool {
+    open_direct(path).is_ok()
+}
+
+pub fn open_direct(path: &Path) -> io::Result<File> {
+    OpenOptions::new().read(true).custom_flags(libc::O_DIRECT).open(path)
+}
+
+/// Byte offset and length of the scoops of one stagger in a plot file.
+pub fn scoop_range(stagger_size: u64, stagger: u64, scoop_num: u16) -> (usize, usize) {
+    let scoops_len = stagger_size as usize * HASH_SIZE * 2;
+    let offset = stagger as usize * stagger_size as usize * PLOT_SIZE +
+                 scoop_num as usize * scoops_len;
+    (offset, scoops_len)
+}
+
+/// The whole blocks a direct read of `len` bytes at `offset` has to cover.
+pub fn direct_window(offset: usize, len: usize) -> (usize, usize) {
+    let start = offset / DIRECT_ALIGN * DIRECT_ALIGN;
+    let end = (offset + len + DIRECT_ALIGN - 1) / DIRECT_ALIGN * DIRECT_ALIGN;
+    (start, end - start)
+}
+
+/// A buffer starting on a `DIRECT_ALIGN` boundary.
+pub struct AlignedBuffer {
+    data: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new() -> AlignedBuffer {
+        AlignedBuffer {
+            data: Vec::new(),
+            start: 0,
+            len: 0,
+        }
+    }
+
+    /// Makes room for `len` bytes, the contents are undefined afterwards.
+    pub fn resize(&mut self, len: usize) {
+        if self.data.len() < len + DIRECT_ALIGN {
+            self.data = vec![0; len + DIRECT_ALIGN];
+        }
+        // a Vec only promises the alignment of u8, so the buffer starts at the first aligned byte
+        let skew = self.data.as_ptr() as usize % DIRECT_ALIGN;
+        self.start = if skew == 0 { 0 } else { DIRECT_ALIGN - skew };
+        self.len = len;
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..self.start + self.len]
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.start..self.start + self.len]
+    }
+}
+
+/// A plot file mapped into memory once for a whole round.
+///
+/// A round only reads one scoop of every stagger, a few KiB spread across the whole file, so the
+/// kernel's own readahead is turned off and the scoops of the next stagger are requested
+/// explicitly while the current one is hashed. Scoops are copied out of the mapping under a
+/// SIGBUS guard, so a file truncated during the round or a failing disk is a read error.
+pub struct MappedPlot {
+    map: Mmap,
+    stagger_size: u64,
+    page_size: usize,
+    buffer: Vec<u8>,
+}
+
+impl MappedPlot {
+    pub fn open(plot: &Plot) -> Result<MappedPlot, String> {
+        let file = try!(File::open(&plot.path).map_err(|e| format!("opening: {}", e)));
+        let map = try!(Mmap::open(&file, Protection::Read).map_err(|e| format!("mapping: {}", e)));
+        let mapped = MappedPlot {
+            map: map,
+            stagger_size: plot.stagger_size,
+            page_size: unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize,
+            buffer: Vec::new(),
+        };
+        mapped.advise(0, mapped.map.len(), libc::MADV_RANDOM);
+        Ok(mapped)
+    }
+
+    /// The scoops of all nonces in a stagger.
+    pub fn scoops(&mut self, stagger: u64, scoop_num: u16) -> Result<&[u8], ReadError> {
+        let (offset, len) = self.scoop_range(stagger, scoop_num);
+        if offset + len > self.map.len() {
+            return Err(ReadError::PastEnd);
+        }
+        self.buffer.resize(len, 0);
+        let map = unsafe { self.map.as_slice() };
+        if !guard::copy_from_mapping(&mut self.buffer, &map[offset..offset + len]) {
+            return Err(ReadError::Failed(String::from("bus error reading the mapping, the file \
+                                                       was truncated or the disk failed")));
+        }
+        Ok(&self.buffer)
+    }
+
+    /// Asks the kernel to start reading the scoops of a stagger that will be needed soon.
+    pub fn prefetch(&self, stagger: u64, scoop_num: u16) {
+        let (offset, len) = self.scoop_range(stagger, scoop_num);
+        if offset + len <= self.map.len() {
+            self.advise(offset, len, libc::MADV_WILLNEED);
+        }
+    }
+
+    fn scoop_range(&self, stagger: u64, scoop_num: u16) -> (usize, usize) {
+        scoop_range(self.stagger_size, stagger, scoop_num)
+    }
+
+    fn advise(&self, offset: usize, len: usize, advice: libc::c_int) {
+        // madvise wants a page aligned start, the mapping itself starts on a page
+        let aligned = offset / self.page_size * self.page_size;
+        unsafe {
+            libc::madvise(self.map.ptr().offset(aligned as isize) as *mut libc::c_void,
+                          len + offset - aligned,
+                          advice);
+        }
+    }
+}
+
+/// A plot file read with `O_DIRECT`, so mining doesn't push everything else out of the page
+/// cache, or with plain `pread` for the `pread` backend.
+///
+/// Every read covers the whole blocks around the scoops of a stagger and lands in a buffer that
+/// is reused for the whole round.
+pub struct DirectPlot {
+    file: File,
+    stagger_size: u64,
+    buffer: AlignedBuffer,
+}
+
+impl DirectPlot {
+    pub fn open(plot: &Plot) -> Result<DirectPlot, String> {
+        let file = try!(open_direct(&plot.path).map_err(|e| format!("opening: {}", e)));
+        Ok(DirectPlot::from_file(plot, file))
+    }
+
+    /// Reads the same way without `O_DIRECT`, so the reads go through the page cache.
+    pub fn open_cached(plot: &Plot) -> Result<DirectPlot, String> {
+        let file = try!(File::open(&plot.path).map_err(|e| format!("opening: {}", e)));
+        Ok(DirectPlot::from_file(plot, file))
+    }
+
+    fn from_file(plot: &Plot, file: File) -> DirectPlot {
+        DirectPlot {
+            file: file,
+            stagger_size: plot.stagger_size,
+            buffer: AlignedBuffer::new(),
+        }
+    }
+
+    pub fn scoops(&mut self, stagger: u64, scoop_num: u16) -> Result<&[u8], ReadError> {
+        let (offset, len) = scoop_range(self.stagger_size, stagger, scoop_num);
+        let (read_start, read_len) = direct_window(offset, len);
+        self.buffer.resize(read_len);
+
+        let mut filled = 0;
+        {
+            let buffer = self.buffer.as_mut_slice();
+            while filled < read_len {
+                match self.file.read_at(&mut buffer[filled..], (read_start + filled) as u64) {
+                    Ok(0) => break,
+                    Ok(read) => filled += read,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        return Err(ReadError::Failed(format!("reading at {}: {}",
+                                                             read_start + filled,
+                                                             e)))
+                    }
+                }
+            }
+        }
+        let start = offset - read_start;
+        if filled < start + len {
+            return Err(ReadError::PastEnd);
+        }
+        Ok(&self.buffer.as_slice()[start..start + len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots::{self, Plot};
+    use std::fs::{File, OpenOptions};
+    use std::path::Path;
+    use testing::TempDir;
+
+    #[test]
+    fn reads_scoops_of_bundled_plot() {
+        let plot = Plot::from_path(Path::new("plots/15250033402865692318_0_8_2"))
+            .unwrap()
+            .unwrap();
+        let mut mapped = MappedPlot::open(&plot).unwrap();
+        let nonce = plots::plot_nonce(plot.account_id, 6);
+        let scoop_len = HASH_SIZE * 2;
+
+        mapped.prefetch(3, 1614);
+        let scoops = mapped.scoops(3, 1614).unwrap();
+        assert_eq!(scoops.len(), 2 * scoop_len);
+        // nonce 6 is the first of the last stagger
+        assert_eq!(&scoops[..scoop_len], &nonce[1614 * scoop_len..1615 * scoop_len]);
+        assert!(mapped.scoops(4, 0).is_err());
+    }
+
+    #[test]
+    fn survives_truncation_under_the_mapping() {
+        let dir = TempDir::new("truncated");
+        let path = dir.join("1_0_8_8");
+        File::create(&path).unwrap().set_len(8 * PLOT_SIZE as u64).unwrap();
+        let plot = Plot::from_path(&path).unwrap().unwrap();
+        let mut mapped = MappedPlot::open(&plot).unwrap();
+        assert!(mapped.scoops(0, 4095).is_ok());
+
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(0).unwrap();
+        let error = mapped.scoops(0, 4095).unwrap_err();
+        assert!(format!("{}", error).starts_with("bus error"), "{}", error);
+    }
+
+    #[test]
+    fn direct_reads_match_mapped_reads() {
+        let mut plot = Plot::from_path(Path::new("plots/15250033402865692318_8_8_2"))
+            .unwrap()
+            .unwrap();
+        let mut mapped = MappedPlot::open(&plot).unwrap();
+        plot.read_backend = ReadBackend::Pread;
+        let mut reader = PlotReader::open(&plot).unwrap();
+        assert_eq!(reader.scoops(1, 2570).unwrap(), &mapped.scoops(1, 2570).unwrap()[..]);
+
+        if !supports_direct(&plot.path) {
+            println!("skipped, the file system of plots/ rejects O_DIRECT");
+            return;
+        }
+        plot.read_backend = ReadBackend::Direct;
+        let mut reader = PlotReader::open(&plot).unwrap();
+        for &(stagger, scoop_num) in &[(0, 0), (1, 2570), (3, 4095)] {
+            assert_eq!(reader.scoops(stagger, scoop_num).unwrap(),
+                       &mapped.scoops(stagger, scoop_num).unwrap()[..]);
+        }
+        assert_eq!(reader.scoops(4, 0), Err(ReadError::PastEnd));
+    }
+}
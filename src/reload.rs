@@ -0,0 +1,63 @@
+use std::fs;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// Fields a reload doesn't change, they are read once when the miner starts.
+pub const RESTART_FIELDS: &'static [&'static str] = &["capture_file",
+                                                      "read_retries",
+                                                      "quarantine_after",
+                                                      "watch_config"];
+
+/// Checks the config file every `interval` and calls `changed` when it was written to.
+///
+/// A file that can't be read, e.g. while an editor replaces it, counts as unchanged until it is
+/// back.
+pub fn watch<F>(path: PathBuf, interval: Duration, mut changed: F)
+    where F: FnMut() + Send + 'static
+{
+    thread::spawn(move || {
+        let mut last = stamp(&path);
+        loop {
+            thread::sleep(interval);
+            let current = stamp(&path);
+            if current.is_some() && current != last {
+                last = current;
+                changed();
+            }
+        }
+    });
+}
+
+/// When the file was last written and how long it is.
+fn stamp(path: &PathBuf) -> Option<(SystemTime, u64)> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified().map(|modified| (modified, metadata.len())))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+    use std::io::Write;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use testing::TempDir;
+
+    #[test]
+    fn notices_writes_to_the_file() {
+        let dir = TempDir::new("watch");
+        let path = dir.join("config.json");
+        File::create(&path).unwrap().write_all(b"{}").unwrap();
+        let (changes, changes_recv) = channel();
+        watch(path.clone(),
+              Duration::from_millis(20),
+              move || changes.send(()).unwrap());
+
+        thread::sleep(Duration::from_millis(100));
+        assert!(changes_recv.try_recv().is_err());
+        File::create(&path).unwrap().write_all(b"{\"scan_depth\": 1}").unwrap();
+        assert!(changes_recv.recv_timeout(Duration::from_secs(2)).is_ok());
+    }
+}
@@ -0,0 +1,326 @@
+use capture::CaptureEntry;
+use cli::Args;
+use devices;
+use miner::{self, MinerWork, NonceSubmitter, RoundResult};
+use plots;
+use pool::{self, MiningInfo};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+/// Seconds between blocks when replaying at real speed.
+const BLOCK_TIME: f64 = 240.0;
+
+/// Stands in for the pool: nothing is sent anywhere, finished rounds are handed back to the
+/// replay loop.
+#[derive(Clone)]
+struct ReplaySubmitter {
+    results: Sender<(MinerWork, RoundResult)>,
+}
+
+impl NonceSubmitter for ReplaySubmitter {
+    fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+        Ok(String::from("replay"))
+    }
+
+    fn round_finished(&self, miner_work: &MinerWork, result: &RoundResult) {
+        // the replay loop may already be gone after the last block
+        let _ = self.results.send((*miner_work, result.clone()));
+    }
+}
+
+#[derive(RustcEncodable)]
+struct BlockBest {
+    height: u64,
+    generation_signature: String,
+    account_id: u64,
+    nonce: u64,
+    deadline: u64,
+    interrupted: bool,
+}
+
+struct PendingBlock {
+    height: u64,
+    generation_signature: String,
+    base_target: u64,
+    reports: usize,
+    interrupted: bool,
+    best: BTreeMap<u64, miner::AccountBest>,
+}
+
+/// `replay <mining_info_file> <result_file> [--speed <factor>|max] [--config <path>] [options]`
+///
+/// Feeds recorded mining infos, one JSON object per line or a capture file, to the miner threads
+/// and writes the best deadline of every account for every block to the result file, one JSON
+/// object per line. With a speed factor blocks arrive as far apart as they did in the capture,
+/// or every 240s, divided by the factor and slow rounds are cut short like they would be live,
+/// `max` mines every block to the end.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let args = try!(Args::parse(args, &["speed"]));
+    if args.positional.len() != 2 {
+        return Err(String::from("expected <mining_info_file> <result_file>"));
+    }
+    let speed = match args.options.get("speed").map(|speed| speed.as_str()) {
+        None | Some("max") => None,
+        Some(speed) => {
+            match speed.parse::<f64>() {
+                Ok(factor) if factor > 0.0 => Some(factor),
+                _ => return Err(format!("invalid speed {}", speed)),
+            }
+        }
+    };
+
+    let mining_infos = try!(read_mining_infos(Path::new(&args.positional[0])));
+    let mut output = try!(File::create(&args.positional[1])
+        .map_err(|e| format!("creating {}: {}", args.positional[1], e)));
+
+    let miner_config = try!(args.load_config(&["plot_folders"]));
+    let scan_options = try!(miner_config.scan_options());
+    let read_errors = miner_config.read_errors();
+    let plot_folders = match miner_config.folder_paths() {
+        Some(plot_folders) => try!(plots::get_usable_plots(plot_folders, &scan_options)),
+        None => return Err(String::from("no plot_folders configured")),
+    };
+
+    let (result_sender, result_recv) = channel();
+    let groups = devices::group(&plot_folders,
+                                &miner_config.device_threads.unwrap_or_default());
+    let signature_senders = miner::spawn(&groups,
+                                         ReplaySubmitter { results: result_sender },
+                                         &read_errors);
+    let miner_count = signature_senders.len();
+    // by the index of the block in the input, a recording may hold the same block twice
+    let mut pending: BTreeMap<usize, PendingBlock> = BTreeMap::new();
+
+    let replay_start = Instant::now();
+    let block_times: Vec<Option<u64>> = mining_infos.windows(2)
+        .map(|pair| match (pair[0].1, pair[1].1) {
+            (Some(start), Some(end)) if end >= start => Some(end - start),
+            _ => None,
+        })
+        .collect();
+    for (i, (mining_info, _)) in mining_infos.into_iter().enumerate() {
+        let miner_work = try!(MinerWork::from_mining_info(mining_info.clone())
+            .map_err(|e| format!("block {}: {:?}", i + 1, e)));
+        println!("replaying block {} height {} scoop {}",
+                 i + 1,
+                 mining_info.height,
+                 miner_work.scoop_num);
+        pending.insert(i,
+                       PendingBlock {
+                           height: mining_info.height,
+                           generation_signature: miner_work.hasher[0..32].to_hex(),
+                           base_target: mining_info.base_target,
+                           reports: 0,
+                           interrupted: false,
+                           best: BTreeMap::new(),
+                       });
+        for sender in &signature_senders {
+            try!(sender.send(miner_work).map_err(|_| String::from("miner thread died")));
+        }
+
+        match speed {
+            None => {
+                // every miner finishes the block before the next one is sent
+                while pending.len() > 0 {
+                    let report = try!(result_recv.recv()
+                        .map_err(|_| String::from("miner threads died")));
+                    try!(collect(report, miner_count, &mut pending, &mut output));
+                }
+            }
+            Some(factor) => {
+                // captured blocks keep the pool's timing, everything else gets the average
+                let block_time = match block_times.get(i) {
+                    Some(&Some(millis)) => millis as f64 / 1000.0,
+                    _ => BLOCK_TIME,
+                };
+                let block_end = Instant::now() + Duration::from_millis((block_time / factor *
+                                                                         1000.0) as u64);
+                loop {
+                    let now = Instant::now();
+                    if now >= block_end {
+                        break;
+                    }
+                    match result_recv.recv_timeout(block_end - now) {
+                        Ok(report) => {
+                            try!(collect(report, miner_count, &mut pending, &mut output))
+                        }
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            return Err(String::from("miner threads died"))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // the miner threads stop once they finish their last round and see no more work coming
+    drop(signature_senders);
+    try!(drain(result_recv, miner_count, &mut pending, &mut output));
+    println!("replay finished in {:?}", Instant::now() - replay_start);
+    Ok(())
+}
+
+/// Reads mining infos and, for capture files, when the pool handed them out.
+///
+/// Plain files hold one `getMiningInfo` response per line. Capture files written by a pool
+/// with `capture_file` set are reduced to the responses that announced a new block.
+fn read_mining_infos(path: &Path) -> Result<Vec<(MiningInfo, Option<u64>)>, String> {
+    let file = try!(File::open(path).map_err(|e| format!("opening {:?}: {}", path, e)));
+    let mut mining_infos: Vec<(MiningInfo, Option<u64>)> = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = try!(line.map_err(|e| format!("reading {:?}: {}", path, e)));
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Ok(entry) = json::decode::<CaptureEntry>(&line) {
+            let mining_info = match entry.response {
+                Some(ref response) if entry.request_type == "getMiningInfo" => {
+                    json::decode::<MiningInfo>(response).ok()
+                }
+                _ => None,
+            };
+            if let Some(mining_info) = mining_info {
+                let is_new = match mining_infos.last() {
+                    Some(&(ref last, _)) => {
+                        last.generation_signature != mining_info.generation_signature
+                    }
+                    None => true,
+                };
+                if is_new {
+                    mining_infos.push((mining_info, Some(entry.timestamp)));
+                }
+            }
+            continue;
+        }
+        mining_infos.push((try!(json::decode::<MiningInfo>(&line)
+                              .map_err(|e| format!("{:?} line {}: {}", path, i + 1, e))),
+                           None));
+    }
+    Ok(mining_infos)
+}
+
+fn drain(result_recv: Receiver<(MinerWork, RoundResult)>,
+         miner_count: usize,
+         pending: &mut BTreeMap<usize, PendingBlock>,
+         output: &mut File)
+         -> Result<(), String> {
+    for report in result_recv.iter() {
+        try!(collect(report, miner_count, pending, output));
+    }
+    Ok(())
+}
+
+/// Adds a finished round to its block and writes the block once every miner thread reported.
+///
+/// Every miner thread mines the blocks in the order they were sent, so a round belongs to the
+/// first block with its height and generation signature that is still waiting for reports.
+fn collect(report: (MinerWork, RoundResult),
+           miner_count: usize,
+           pending: &mut BTreeMap<usize, PendingBlock>,
+           output: &mut File)
+           -> Result<(), String> {
+    let (miner_work, result) = report;
+    let generation_signature = miner_work.hasher[0..32].to_hex();
+    let found = pending.iter_mut().find(|&(_, ref block)| {
+        block.height == miner_work.height && block.generation_signature == generation_signature &&
+        block.reports < miner_count
+    });
+    let (index, done) = match found {
+        Some((&index, block)) => {
+            block.reports += 1;
+            block.interrupted |= result.interrupted;
+            for candidate in result.best {
+                let better = match block.best.get(&candidate.account_id) {
+                    Some(existing) => candidate.hash < existing.hash,
+                    None => true,
+                };
+                if better {
+                    block.best.insert(candidate.account_id, candidate);
+                }
+            }
+            (index, block.reports == miner_count)
+        }
+        None => return Ok(()),
+    };
+    if !done {
+        return Ok(());
+    }
+
+    let block = pending.remove(&index).unwrap();
+    for best in block.best.values() {
+        let line = json::encode(&BlockBest {
+                height: block.height,
+                generation_signature: block.generation_signature.clone(),
+                account_id: best.account_id,
+                nonce: best.nonce,
+                deadline: best.hash / block.base_target,
+                interrupted: block.interrupted,
+            })
+            .unwrap();
+        try!(writeln!(output, "{}", line).map_err(|e| format!("writing results: {}", e)));
+    }
+    println!("block {} done: {} accounts{}",
+             block.height,
+             block.best.len(),
+             if block.interrupted { ", interrupted" } else { "" });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs::File;
+    use std::io::{Read, Write};
+    use testing::TempDir;
+
+    #[test]
+    fn replays_blocks_to_completion() {
+        let dir = TempDir::new("replay");
+        let infos_path = dir.join("infos.jsonl");
+        let results_path = dir.join("results.jsonl");
+        let config_path = dir.join("config.json");
+
+        let mut infos = File::create(&infos_path).unwrap();
+        let first = ("2c1f1bc2a2bf8b4c1d8e2fbd4a7b9b1c7d85e5ca13a0f6c9e0c1c4f7b8e2d3a1", 480000);
+        let second = ("d6a8bd1f9b1eaf3f6f47bb4cd79e8b3a1e64c1a5f27f2bd9c4b6b8a9e0e5f6c2", 480001);
+        // a recording may hold a block twice, e.g. after the pool went back to it
+        for &(sig, height) in &[first, second, first] {
+            writeln!(infos,
+                     "{{\"generationSignature\":\"{}\",\"baseTarget\":18325193796,\
+                      \"requestProcessingTime\":0,\"height\":{},\"targetDeadline\":0}}",
+                     sig,
+                     height)
+                .unwrap();
+        }
+        File::create(&config_path)
+            .unwrap()
+            .write_all(format!("{{\"plot_folders\":[\"{}\"]}}",
+                               env::current_dir().unwrap().join("plots").display())
+                .as_bytes())
+            .unwrap();
+
+        run(&[infos_path.to_str().unwrap().to_owned(),
+              results_path.to_str().unwrap().to_owned(),
+              format!("-config={}", config_path.display())])
+            .unwrap();
+
+        let mut results = String::new();
+        File::open(&results_path).unwrap().read_to_string(&mut results).unwrap();
+
+        let lines: Vec<&str> = results.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].contains("\"height\":480000"));
+        assert!(lines[0].contains("\"nonce\":6,\"deadline\":37542209,\"interrupted\":false"));
+        assert!(lines[1].contains("\"height\":480001"));
+        assert!(lines[1].contains("\"nonce\":5,\"deadline\":104716254,\"interrupted\":false"));
+        assert_eq!(lines[2], lines[0]);
+    }
+}
@@ -0,0 +1,354 @@
+use constants::*;
+use devices::{self, MinerGroups};
+use miner::{self, MinerWork, NonceSubmitter, SharedPlots};
+use overlap;
+use plots::{self, Plot, PlotFolders, ScanOptions};
+use pool::Pool;
+use read_errors::ReadErrors;
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+/// Keeps the plots the miner threads read in line with what is on disk.
+///
+/// Every rescan walks the configured plot folders again. Plots that showed up are added to the
+/// threads of their device, deleted ones are dropped, and devices that are new, e.g. a fresh
+/// drive matching a glob pattern, get miner threads of their own. Threads pick up their new plots
+/// when they start the next round.
+pub struct Rescan<S> {
+    folder_paths: Vec<String>,
+    options: ScanOptions,
+    device_threads: BTreeMap<String, u32>,
+    submitter: S,
+    errors: ReadErrors,
+    miners: BTreeMap<(String, u32), SharedPlots>,
+}
+
+/// What a reloaded configuration changes about scanning.
+pub struct RescanSettings {
+    pub folder_paths: Vec<String>,
+    pub options: ScanOptions,
+    pub device_threads: BTreeMap<String, u32>,
+    /// time between rescans, none to only rescan after a reload
+    pub interval: Option<Duration>,
+}
+
+impl<S> Rescan<S>
+    where S: NonceSubmitter + Clone + Send + 'static
+{
+    /// Starts the miner threads of every device with plots on it and returns the senders to
+    /// feed them work.
+    pub fn spawn(folder_paths: Vec<String>,
+                 options: ScanOptions,
+                 device_threads: BTreeMap<String, u32>,
+                 plot_folders: &PlotFolders,
+                 submitter: S,
+                 errors: ReadErrors)
+                 -> (Rescan<S>, Vec<Sender<MinerWork>>) {
+        let groups = devices::group(plot_folders, &device_threads);
+        for line in devices::report(&groups) {
+            info!("{}", line);
+        }
+        let mut rescan = Rescan {
+            folder_paths: folder_paths,
+            options: options,
+            device_threads: device_threads,
+            submitter: submitter,
+            errors: errors,
+            miners: BTreeMap::new(),
+        };
+        let mut senders = Vec::new();
+        for (key, plots) in groups {
+            senders.push(rescan.spawn_miner(key, plots));
+        }
+        (rescan, senders)
+    }
+
+    fn spawn_miner(&mut self, key: (String, u32), plots: Vec<Plot>) -> Sender<MinerWork> {
+        let (shared_plots, sender) = miner::spawn_miner(miner::thread_name(&key),
+                                                        plots,
+                                                        self.submitter.clone(),
+                                                        self.errors.clone());
+        self.miners.insert(key, shared_plots);
+        sender
+    }
+
+    /// Scans with `settings` from now on. Devices that need fewer threads than before keep the
+    /// threads they have, without plots.
+    pub fn reconfigure(&mut self, settings: RescanSettings) {
+        self.folder_paths = settings.folder_paths;
+        // a reloaded folder keeps its throttle unless its rate changed
+        let throttles = self.options.throttles.clone();
+        self.options = settings.options;
+        self.options.throttles = throttles;
+        self.device_threads = settings.device_threads;
+    }
+
+    /// Scans the plot folders again, logs what changed and returns the senders of the miner
+    /// threads started for new folders.
+    pub fn rescan(&mut self) -> Vec<Sender<MinerWork>> {
+        let mut plot_folders = plots::get_plots(self.folder_paths.clone(), &self.options);
+        // quarantined plots stay out until the miner restarts
+        for folder in &mut plot_folders.folders {
+            let errors = &self.errors;
+            folder.plots.retain(|plot| !errors.is_quarantined(&plot.path));
+        }
+        if self.options.exclude_overlaps {
+            let overlaps = overlap::analyze(&plot_folders);
+            overlap::exclude_redundant(&mut plot_folders, &overlaps);
+        }
+
+        let old_nonces = self.usable_nonces();
+        let mut found: MinerGroups = devices::group(&plot_folders, &self.device_threads);
+
+        // over all threads, regrouping moves plots from one thread to another
+        let old_plots: Vec<Plot> = self.miners
+            .values()
+            .flat_map(|shared_plots| shared_plots.lock().unwrap().clone())
+            .collect();
+        let new_plots: Vec<Plot> = found.values().flat_map(|plots| plots.clone()).collect();
+        let (removed, added) = changes(&old_plots, &new_plots);
+        for plot in &removed {
+            info!("plot removed: {:?}", plot.path);
+        }
+        for plot in &added {
+            info!("plot added: {:?}", plot.path);
+        }
+
+        for (key, shared_plots) in &self.miners {
+            // a device that disappeared keeps its idle thread in case it comes back
+            *shared_plots.lock().unwrap() = found.remove(key).unwrap_or_default();
+        }
+        let mut senders = Vec::new();
+        for (key, new_plots) in found {
+            info!("new device {} thread {}, starting a miner thread", key.0, key.1);
+            senders.push(self.spawn_miner(key, new_plots));
+        }
+
+        let new_nonces = self.usable_nonces();
+        if !added.is_empty() || !removed.is_empty() || old_nonces != new_nonces {
+            info!("plots rescanned: {} added, {} removed, capacity {} GiB -> {} GiB",
+                  added.len(),
+                  removed.len(),
+                  gib(old_nonces),
+                  gib(new_nonces));
+        }
+        senders
+    }
+
+    fn usable_nonces(&self) -> u64 {
+        self.miners
+            .values()
+            .map(|shared_plots| {
+                shared_plots.lock()
+                    .unwrap()
+                    .iter()
+                    .map(|plot| plot.usable_nonces())
+                    .sum::<u64>()
+            })
+            .sum()
+    }
+}
+
+/// The plots of `old` that aren't in `new` and the plots of `new` that weren't in `old`.
+fn changes<'a>(old: &'a [Plot], new: &'a [Plot]) -> (Vec<&'a Plot>, Vec<&'a Plot>) {
+    (old.iter().filter(|plot| !contains(new, plot)).collect(),
+     new.iter().filter(|plot| !contains(old, plot)).collect())
+}
+
+/// Whether an equal plot is in `plots`: same file, same part of it minable.
+fn contains(plots: &[Plot], plot: &Plot) -> bool {
+    plots.iter().any(|other| {
+        other.path == plot.path && other.usable_nonces() == plot.usable_nonces() &&
+        other.excluded == plot.excluded
+    })
+}
+
+fn gib(nonces: u64) -> String {
+    format!("{:.2}", nonces as f64 * PLOT_SIZE as f64 / 1024.0 / 1024.0 / 1024.0)
+}
+
+/// Rescans every `interval`, and right away with the settings sent to the returned sender, and
+/// subscribes the threads of new folders to the pool.
+pub fn start(mut rescan: Rescan<Pool>,
+             interval: Option<Duration>,
+             pool: Pool)
+             -> Sender<RescanSettings> {
+    let (settings, settings_recv) = channel::<RescanSettings>();
+    thread::spawn(move || {
+        let mut interval = interval;
+        loop {
+            let received = match interval {
+                Some(interval) => settings_recv.recv_timeout(interval),
+                None => settings_recv.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(settings) => {
+                    interval = settings.interval;
+                    rescan.reconfigure(settings);
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+            for sender in rescan.rescan() {
+                pool.add_subscriber(sender).unwrap();
+            }
+        }
+    });
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use devices::{self, MinerGroups};
+    use miner::{self, MinerWork, NonceSubmitter, SharedPlots};
+    use mock_pool::mining_info_json;
+    use plots::{self, Plot, ScanOptions};
+    use pool;
+    use read_errors::ReadErrors;
+    use reader::ReadBackend;
+    use rustc_serialize::json;
+    use std::collections::BTreeMap;
+    use std::fs::{self, File};
+    use std::path::Path;
+    use std::sync::Mutex;
+    use std::sync::mpsc::channel;
+    use testing::{FailingReads, TempDir};
+
+    #[derive(Clone)]
+    struct NullSubmitter;
+
+    impl NonceSubmitter for NullSubmitter {
+        fn submit_nonce(&self, _nonce: u64, _account_id: u64) -> Result<String, pool::Error> {
+            Ok(String::new())
+        }
+    }
+
+    fn write_plot(path: &Path) {
+        File::create(path).unwrap().set_len(8 * PLOT_SIZE as u64).unwrap();
+    }
+
+    fn plot_names(shared_plots: &SharedPlots) -> Vec<String> {
+        shared_plots.lock()
+            .unwrap()
+            .iter()
+            .map(|plot| plot.path.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn picks_up_added_and_removed_plots() {
+        let dir = TempDir::new("rescan");
+        fs::create_dir(dir.join("disk1")).unwrap();
+        write_plot(&dir.join("disk1/1_0_8_8"));
+
+        let folder_paths = vec![format!("{}/disk*", dir.path().display())];
+        let options = ScanOptions::default();
+        let plot_folders = plots::get_plots(folder_paths.clone(), &options);
+        let (mut rescan, senders) =
+            Rescan::spawn(folder_paths,
+                          options,
+                          BTreeMap::new(),
+                          &plot_folders,
+                          NullSubmitter,
+                          ReadErrors::new(0, 1));
+        assert_eq!(senders.len(), 1);
+        // everything in the temp dir is on one device
+        let key = rescan.miners.keys().next().unwrap().clone();
+        assert_eq!(rescan.usable_nonces(), 8);
+
+        write_plot(&dir.join("disk1/1_8_8_8"));
+        assert!(rescan.rescan().is_empty());
+        assert_eq!(plot_names(&rescan.miners[&key]), vec!["1_0_8_8", "1_8_8_8"]);
+
+        fs::remove_file(dir.join("disk1/1_0_8_8")).unwrap();
+        fs::create_dir_all(dir.join("disk2")).unwrap();
+        write_plot(&dir.join("disk2/1_16_8_8"));
+        let new_senders = rescan.rescan();
+        let rescanned = (plot_names(&rescan.miners[&key]), rescan.usable_nonces());
+
+        // a reload leaves out the folders that aren't configured anymore
+        rescan.reconfigure(RescanSettings {
+            folder_paths: vec![format!("{}/disk2", dir.path().display())],
+            options: ScanOptions::default(),
+            device_threads: BTreeMap::new(),
+            interval: None,
+        });
+        let reloaded_senders = rescan.rescan();
+
+        assert!(new_senders.is_empty());
+        assert_eq!(rescanned,
+                   (vec![String::from("1_8_8_8"), String::from("1_16_8_8")], 16));
+        assert!(reloaded_senders.is_empty());
+        assert_eq!(plot_names(&rescan.miners[&key]), vec!["1_16_8_8"]);
+        assert_eq!(rescan.usable_nonces(), 8);
+    }
+
+    #[test]
+    fn quarantined_plots_stay_out_after_a_rescan() {
+        for &backend in &[ReadBackend::Mmap, ReadBackend::Uring] {
+            let dir = TempDir::new(&format!("quarantine-{:?}", backend));
+            write_plot(&dir.join("1_0_8_2"));
+            write_plot(&dir.join("1_8_8_2"));
+            let source = FailingReads::new().fail_staggers(&dir.join("1_0_8_2"), &[0, 1, 2, 3]);
+
+            let folder_paths = vec![dir.path().display().to_string()];
+            let options = ScanOptions {
+                read_backend: backend,
+                ..ScanOptions::default()
+            };
+            let plot_folders = plots::get_plots(folder_paths.clone(), &options);
+            let errors = ReadErrors::new(0, 1);
+            let (mut rescan, _) = Rescan::spawn(folder_paths,
+                                                options,
+                                                BTreeMap::new(),
+                                                &plot_folders,
+                                                NullSubmitter,
+                                                errors.clone());
+            let key = rescan.miners.keys().next().unwrap().clone();
+
+            // a round reading through `source` with the plots of the miner thread
+            let info = json::decode(&mining_info_json(&"00".repeat(32), 18325193796, 480000, 0))
+                .unwrap();
+            let (signature_sender, signature_recv) = channel();
+            signature_sender.send(MinerWork::from_mining_info(info).unwrap()).unwrap();
+            drop(signature_sender);
+            miner::mine(NullSubmitter,
+                        &source,
+                        &signature_recv,
+                        rescan.miners[&key].clone(),
+                        errors.clone(),
+                        &Mutex::new(None));
+
+            // intact on disk, the rescan would take it back without the quarantine
+            rescan.rescan();
+            assert!(errors.is_quarantined(&dir.join("1_0_8_2")), "{:?}", backend);
+            assert_eq!(plot_names(&rescan.miners[&key]), vec!["1_8_8_2"], "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn plots_moving_to_another_thread_are_not_changes() {
+        let dir = TempDir::new("regroup");
+        write_plot(&dir.join("1_0_8_8"));
+        write_plot(&dir.join("1_8_8_8"));
+
+        let plot_folders = plots::get_plots(vec![dir.path().display().to_string()],
+                                            &ScanOptions::default());
+        let one_thread = devices::group(&plot_folders, &BTreeMap::new());
+        let device = one_thread.keys().next().unwrap().0.clone();
+        let mut device_threads = BTreeMap::new();
+        device_threads.insert(device, 2);
+        let two_threads = devices::group(&plot_folders, &device_threads);
+        assert_eq!((one_thread.len(), two_threads.len()), (1, 2));
+
+        let all = |groups: &MinerGroups| -> Vec<Plot> {
+            groups.values().flat_map(|plots| plots.clone()).collect()
+        };
+        let (old, new) = (all(&one_thread), all(&two_threads));
+        let (removed, added) = changes(&old, &new);
+        assert!(removed.is_empty() && added.is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+use libc;
+use std::io;
+use std::mem;
+
+/// SIGINT and SIGTERM, the signals that stop the miner, and SIGHUP, which reloads the
+/// configuration.
+fn handled_signals() -> libc::sigset_t {
+    unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        libc::sigaddset(&mut set, libc::SIGHUP);
+        set
+    }
+}
+
+/// Keeps SIGINT, SIGTERM and SIGHUP from killing the process so `wait_for_signal` can pick them
+/// up.
+///
+/// Has to be called before any other thread is started, threads inherit the signal mask of the
+/// thread that starts them.
+pub fn block_signals() -> Result<(), String> {
+    let set = handled_signals();
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, ::std::ptr::null_mut()) } {
+        0 => Ok(()),
+        e => Err(format!("blocking signals: {}", io::Error::from_raw_os_error(e))),
+    }
+}
+
+/// Waits until SIGINT, SIGTERM or SIGHUP arrives and returns it.
+pub fn wait_for_signal() -> Result<libc::c_int, String> {
+    let set = handled_signals();
+    let mut signal: libc::c_int = 0;
+    match unsafe { libc::sigwait(&set, &mut signal) } {
+        0 => Ok(signal),
+        e => Err(format!("waiting for signals: {}", io::Error::from_raw_os_error(e))),
+    }
+}
+
+pub fn signal_name(signal: libc::c_int) -> String {
+    match signal {
+        libc::SIGINT => String::from("SIGINT"),
+        libc::SIGTERM => String::from("SIGTERM"),
+        libc::SIGHUP => String::from("SIGHUP"),
+        other => format!("signal {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+
+    #[test]
+    fn picks_up_blocked_signals() {
+        // the mask is per thread, blocking it here leaves the other tests alone
+        block_signals().unwrap();
+        unsafe {
+            libc::pthread_kill(libc::pthread_self(), libc::SIGTERM);
+        }
+        assert_eq!(wait_for_signal(), Ok(libc::SIGTERM));
+        assert_eq!(signal_name(libc::SIGTERM), "SIGTERM");
+    }
+}
@@ -36,10 +36,8 @@ pub fn shabal256(input: &[u8]) -> [u8; 32] {
         sph_shabal256(shabal_ctx_ptr,
                       &input[0] as *const _ as *const libc::c_void,
                       input.len());
-        // println!("input:  {}", input.to_hex());
         sph_shabal256_close(shabal_ctx_ptr,
                             &mut output[0] as *mut _ as *mut libc::c_void);
-        // println!("output: {}", output.to_hex());
     }
     return output;
 }
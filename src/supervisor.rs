@@ -0,0 +1,105 @@
+use std::any::Any;
+use std::cmp;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long to wait before restarting a thread that crashed. The wait doubles with every crash
+/// in a row and starts over once the thread ran for at least `max` before crashing again.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    current: Duration,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Backoff {
+        Backoff {
+            initial: initial,
+            max: max,
+            current: initial,
+        }
+    }
+
+    /// The wait before the next restart of a thread that crashed after running for `ran`.
+    pub fn next(&mut self, ran: Duration) -> Duration {
+        if ran >= self.max {
+            self.current = self.initial;
+        }
+        let delay = self.current;
+        self.current = cmp::min(self.current * 2, self.max);
+        delay
+    }
+}
+
+/// Runs the thread `spawn` starts until it finishes, starting it again whenever it panics.
+///
+/// `spawn` is called from the supervisor thread once per start, so it has to hand everything
+/// the new thread needs over to it anew.
+pub fn supervise<F>(name: String, mut backoff: Backoff, mut spawn: F) -> JoinHandle<()>
+    where F: FnMut() -> JoinHandle<()> + Send + 'static
+{
+    thread::spawn(move || {
+        loop {
+            let started = Instant::now();
+            match spawn().join() {
+                Ok(()) => return,
+                Err(panic) => {
+                    let delay = backoff.next(Instant::now() - started);
+                    error!("{} crashed: {}, restarting it in {:?}",
+                           name,
+                           panic_message(&panic),
+                           delay);
+                    thread::sleep(delay);
+                }
+            }
+        }
+    })
+}
+
+pub fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        return (*message).to_owned();
+    }
+    if let Some(message) = panic.downcast_ref::<String>() {
+        return message.clone();
+    }
+    String::from("unknown panic")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn backoff_doubles_up_to_max_and_starts_over() {
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(5));
+        let quick = Duration::from_millis(10);
+        assert_eq!(backoff.next(quick), Duration::from_secs(1));
+        assert_eq!(backoff.next(quick), Duration::from_secs(2));
+        assert_eq!(backoff.next(quick), Duration::from_secs(4));
+        assert_eq!(backoff.next(quick), Duration::from_secs(5));
+        assert_eq!(backoff.next(quick), Duration::from_secs(5));
+        assert_eq!(backoff.next(Duration::from_secs(5)), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn restarts_until_the_thread_finishes() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let thread_starts = starts.clone();
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(10));
+        let supervisor = supervise(String::from("test thread"), backoff, move || {
+            let starts = thread_starts.clone();
+            thread::spawn(move || if starts.fetch_add(1, Ordering::SeqCst) < 2 {
+                panic!("crash");
+            })
+        });
+
+        supervisor.join().unwrap();
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,132 @@
+use plots::Plot;
+use reader::{PlotFiles, ReadError, ReadScoops, ScoopSource};
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use uring::{Completion, ReadStaggers};
+
+/// A fresh folder under the system temp folder for one test, removed again when it is dropped,
+/// also when an assert failed.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates `rminer-<name>-<pid>`, removing whatever a crashed earlier run left there.
+    pub fn new(name: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("rminer-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.path.join(path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Reads the plot files, but every read of the staggers set up with `fail_staggers` fails.
+pub struct FailingReads {
+    failing: BTreeMap<PathBuf, BTreeSet<u64>>,
+}
+
+impl FailingReads {
+    pub fn new() -> FailingReads {
+        FailingReads { failing: BTreeMap::new() }
+    }
+
+    /// Fails every read of `staggers` of the plot at `path`.
+    pub fn fail_staggers(mut self, path: &Path, staggers: &[u64]) -> FailingReads {
+        self.failing
+            .entry(path.to_path_buf())
+            .or_insert_with(BTreeSet::new)
+            .extend(staggers.iter().cloned());
+        self
+    }
+
+    fn failing(&self, plot: &Plot) -> BTreeSet<u64> {
+        self.failing.get(&plot.path).cloned().unwrap_or_default()
+    }
+}
+
+impl ScoopSource for FailingReads {
+    fn open(&self, plot: &Plot) -> Result<Box<ReadScoops>, String> {
+        let reader = try!(PlotFiles.open(plot));
+        Ok(Box::new(FailingReader {
+            reader: reader,
+            failing: self.failing(plot),
+        }))
+    }
+
+    fn stagger_reads<'a>(&self,
+                         plots: &'a [Plot],
+                         scoop_num: u16,
+                         retries: u32)
+                         -> io::Result<Box<ReadStaggers + 'a>> {
+        let reads = try!(PlotFiles.stagger_reads(plots, scoop_num, retries));
+        Ok(Box::new(FailingStaggerReads {
+            reads: reads,
+            failing: plots.iter().map(|plot| self.failing(plot)).collect(),
+            retries: retries,
+        }))
+    }
+}
+
+struct FailingReader {
+    reader: Box<ReadScoops>,
+    failing: BTreeSet<u64>,
+}
+
+impl ReadScoops for FailingReader {
+    fn scoops(&mut self, stagger: u64, scoop_num: u16) -> Result<&[u8], ReadError> {
+        if self.failing.contains(&stagger) {
+            return Err(ReadError::Failed(String::from("failing on purpose")));
+        }
+        self.reader.scoops(stagger, scoop_num)
+    }
+
+    fn prefetch(&self, stagger: u64, scoop_num: u16) {
+        self.reader.prefetch(stagger, scoop_num)
+    }
+}
+
+/// Turns the reads of failing staggers into staggers that failed every try.
+struct FailingStaggerReads<'a> {
+    reads: Box<ReadStaggers + 'a>,
+    /// the failing staggers of every plot
+    failing: Vec<BTreeSet<u64>>,
+    retries: u32,
+}
+
+impl<'a> ReadStaggers for FailingStaggerReads<'a> {
+    fn next<'b>(&'b mut self) -> Option<Completion<'b>> {
+        let failing = &self.failing;
+        match self.reads.next() {
+            Some(Completion::Scoops { plot, stagger, .. }) if failing[plot].contains(&stagger) => {
+                Some(Completion::StaggerFailed {
+                    plot: plot,
+                    stagger: stagger,
+                    error: String::from("failing on purpose"),
+                    retries: self.retries,
+                })
+            }
+            completion => completion,
+        }
+    }
+
+    fn drop_plot(&mut self, plot: usize) {
+        self.reads.drop_plot(plot)
+    }
+}
@@ -0,0 +1,136 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Caps how fast the miner threads read from one plot folder, all its plots share one.
+///
+/// Reads may run up to a second worth of bytes ahead, after that every read waits until the
+/// folder is back at its rate.
+#[derive(Clone)]
+pub struct Throttle {
+    mib_per_sec: u32,
+    bytes_per_sec: f64,
+    /// when bytes were last added to the allowance, and how many may be read right away
+    state: Arc<Mutex<(Instant, f64)>>,
+}
+
+impl Throttle {
+    pub fn new(mib_per_sec: u32) -> Throttle {
+        let bytes_per_sec = mib_per_sec as f64 * 1024.0 * 1024.0;
+        Throttle {
+            mib_per_sec: mib_per_sec,
+            bytes_per_sec: bytes_per_sec,
+            state: Arc::new(Mutex::new((Instant::now(), bytes_per_sec))),
+        }
+    }
+
+    /// Counts `bytes` that were just read, sleeping as long as that takes the folder over its
+    /// rate.
+    pub fn read(&self, bytes: usize) {
+        if let Some(wait) = self.wait(bytes, Instant::now()) {
+            thread::sleep(wait);
+        }
+    }
+
+    /// Counts `bytes` read at `now`, returns how long to wait to be back at the rate.
+    fn wait(&self, bytes: usize, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        // another thread may have taken its time after this one, but got the lock first
+        if now > state.0 {
+            let elapsed = now - state.0;
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+            state.0 = now;
+            state.1 = (state.1 + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        }
+        state.1 -= bytes as f64;
+        match state.1 {
+            allowance if allowance < 0.0 => {
+                let wait = -allowance / self.bytes_per_sec;
+                Some(Duration::new(wait as u64, (wait.fract() * 1e9) as u32))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The throttles of the rate limited folders by path, so a rescan keeps the throttle of a
+/// folder instead of handing it a fresh second of burst.
+#[derive(Clone, Default)]
+pub struct Throttles {
+    by_folder: Arc<Mutex<BTreeMap<PathBuf, Throttle>>>,
+}
+
+impl Throttles {
+    /// The throttle of `folder`, a new one the first time and when its rate changed.
+    pub fn get(&self, folder: &Path, mib_per_sec: u32) -> Throttle {
+        let mut by_folder = self.by_folder.lock().unwrap();
+        match by_folder.get(folder) {
+            Some(throttle) if throttle.mib_per_sec == mib_per_sec => return throttle.clone(),
+            _ => {}
+        }
+        let throttle = Throttle::new(mib_per_sec);
+        by_folder.insert(folder.to_path_buf(), throttle.clone());
+        throttle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    const MIB: usize = 1024 * 1024;
+
+    #[test]
+    fn waits_once_the_burst_is_used_up() {
+        let throttle = Throttle::new(8);
+        let start = throttle.state.lock().unwrap().0;
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        // a second worth of reads right away
+        for _ in 0..8 {
+            assert_eq!(throttle.wait(MIB, at(0)), None);
+        }
+        assert_eq!(throttle.wait(MIB, at(0)), Some(Duration::from_millis(125)));
+        // the sleep paid for that MiB, the next one is due after another 125ms
+        assert_eq!(throttle.wait(MIB, at(125)), Some(Duration::from_millis(125)));
+        // an idle folder saves up a second at most
+        for _ in 0..8 {
+            assert_eq!(throttle.wait(MIB, at(60000)), None);
+        }
+        assert!(throttle.wait(MIB, at(60000)).is_some());
+    }
+
+    #[test]
+    fn holds_threads_to_the_rate_together() {
+        let throttle = Throttle::new(8);
+        let start = Instant::now();
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                let throttle = throttle.clone();
+                thread::spawn(move || for _ in 0..8 {
+                    throttle.read(MIB);
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        // 16 MiB at 8 MiB/s with the first 8 MiB allowed right away, a busy machine only adds
+        let elapsed = Instant::now() - start;
+        assert!(elapsed >= Duration::from_millis(950), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn folders_keep_their_throttle() {
+        let throttles = Throttles::default();
+        let throttle = throttles.get(Path::new("/mnt/usb1"), 8);
+        throttle.read(8 * MIB);
+        assert!(throttles.get(Path::new("/mnt/usb1"), 8).wait(MIB, Instant::now()).is_some());
+        assert!(throttles.get(Path::new("/mnt/usb2"), 8).wait(MIB, Instant::now()).is_none());
+        assert!(throttles.get(Path::new("/mnt/usb1"), 16).wait(MIB, Instant::now()).is_none());
+    }
+}
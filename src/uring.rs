@@ -0,0 +1,572 @@
+use libc;
+use plots::Plot;
+use reader::{self, AlignedBuffer};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// the syscall numbers are the same on every architecture but alpha
+const SYS_IO_URING_SETUP: libc::c_long = 425;
+const SYS_IO_URING_ENTER: libc::c_long = 426;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: libc::c_uint = 1;
+const IORING_OP_READV: u8 = 1;
+
+/// Reads every miner thread keeps in flight.
+const QUEUE_DEPTH: u32 = 32;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqRingOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqRingOffsets,
+    cq_off: CqRingOffsets,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    rw_flags: u32,
+    user_data: u64,
+    pad: [u64; 3],
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// One of the three areas the kernel shares with us for a ring.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Mapping> {
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(),
+                       len,
+                       libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_SHARED | libc::MAP_POPULATE,
+                       fd,
+                       offset)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len: len,
+        })
+    }
+
+    unsafe fn at<T>(&self, offset: u32) -> *mut T {
+        self.ptr.offset(offset as isize) as *mut T
+    }
+
+    unsafe fn atomic(&self, offset: u32) -> &AtomicU32 {
+        &*self.at::<AtomicU32>(offset)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
+
+/// A bare io_uring instance that only knows how to read into buffers.
+pub struct Ring {
+    fd: RawFd,
+    params: Params,
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    /// queued but not yet handed to the kernel
+    unsubmitted: u32,
+}
+
+impl Ring {
+    pub fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        let fd = unsafe {
+            libc::syscall(SYS_IO_URING_SETUP,
+                          entries as libc::c_long,
+                          &mut params as *mut Params)
+        } as RawFd;
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mappings = Mapping::new(fd,
+                                    (params.sq_off.array + params.sq_entries * 4) as usize,
+                                    IORING_OFF_SQ_RING)
+            .and_then(|sq| {
+                Mapping::new(fd,
+                             params.cq_off.cqes as usize +
+                             params.cq_entries as usize * mem::size_of::<Cqe>(),
+                             IORING_OFF_CQ_RING)
+                    .map(|cq| (sq, cq))
+            })
+            .and_then(|(sq, cq)| {
+                Mapping::new(fd,
+                             params.sq_entries as usize * mem::size_of::<Sqe>(),
+                             IORING_OFF_SQES)
+                    .map(|sqes| (sq, cq, sqes))
+            });
+        match mappings {
+            Ok((sq, cq, sqes)) => {
+                Ok(Ring {
+                    fd: fd,
+                    params: params,
+                    sq: sq,
+                    cq: cq,
+                    sqes: sqes,
+                    unsubmitted: 0,
+                })
+            }
+            Err(e) => {
+                unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    /// Queues a read into the buffer `iovec` points to, returns false when the queue is full.
+    ///
+    /// The buffer and the iovec have to stay where they are until the read completes.
+    pub unsafe fn push_read(&mut self,
+                            fd: RawFd,
+                            iovec: *const libc::iovec,
+                            offset: u64,
+                            user_data: u64)
+                            -> bool {
+        let sq_off = &self.params.sq_off;
+        let head = self.sq.atomic(sq_off.head).load(Ordering::Acquire);
+        let tail = self.sq.atomic(sq_off.tail).load(Ordering::Relaxed);
+        if tail.wrapping_sub(head) >= self.params.sq_entries {
+            return false;
+        }
+        let index = tail & *self.sq.at::<u32>(sq_off.ring_mask);
+        *self.sqes.at::<Sqe>(0).offset(index as isize) = Sqe {
+            opcode: IORING_OP_READV,
+            fd: fd,
+            off: offset,
+            addr: iovec as u64,
+            len: 1,
+            user_data: user_data,
+            ..Sqe::default()
+        };
+        *self.sq.at::<u32>(sq_off.array).offset(index as isize) = index;
+        self.sq.atomic(sq_off.tail).store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+        true
+    }
+
+    /// Hands queued reads to the kernel and waits until at least `wait_for` reads completed.
+    pub fn submit_and_wait(&mut self, wait_for: u32) -> io::Result<()> {
+        loop {
+            let submitted = unsafe {
+                libc::syscall(SYS_IO_URING_ENTER,
+                              self.fd as libc::c_long,
+                              self.unsubmitted as libc::c_long,
+                              wait_for as libc::c_long,
+                              IORING_ENTER_GETEVENTS as libc::c_long,
+                              ptr::null::<libc::sigset_t>(),
+                              0 as libc::c_long)
+            };
+            if submitted >= 0 {
+                self.unsubmitted -= submitted as u32;
+                return Ok(());
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+    }
+
+    /// The `user_data` and result of the next completed read, if any.
+    pub fn pop_completion(&mut self) -> Option<(u64, i32)> {
+        let cq_off = &self.params.cq_off;
+        unsafe {
+            let head = self.cq.atomic(cq_off.head).load(Ordering::Relaxed);
+            let tail = self.cq.atomic(cq_off.tail).load(Ordering::Acquire);
+            if head == tail {
+                return None;
+            }
+            let index = head & *self.cq.at::<u32>(cq_off.ring_mask);
+            let cqe = &*self.cq.at::<Cqe>(cq_off.cqes).offset(index as isize);
+            let completion = (cqe.user_data, cqe.res);
+            self.cq.atomic(cq_off.head).store(head.wrapping_add(1), Ordering::Release);
+            Some(completion)
+        }
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Whether this kernel lets us set up a ring, io_uring arrived in Linux 5.1 and is often turned
+/// off in containers.
+pub fn supported() -> bool {
+    Ring::new(1).is_ok()
+}
+
+struct Slot {
+    iovec: libc::iovec,
+    buffer: AlignedBuffer,
+    plot: usize,
+    stagger: u64,
+    /// where the scoops start in the buffer and how long they are
+    scoops: (usize, usize),
+    /// reads of this stagger that failed and were tried again
+    retries: u32,
+}
+
+pub enum Completion<'a> {
+    Scoops {
+        plot: usize,
+        stagger: u64,
+        scoops: &'a [u8],
+        retries: u32,
+    },
+    /// every try to read the stagger failed
+    StaggerFailed {
+        plot: usize,
+        stagger: u64,
+        error: String,
+        retries: u32,
+    },
+    /// the plot can't be read, none of its remaining staggers will come
+    PlotFailed { plot: usize, error: String },
+    /// the ring stopped working, nothing more will come
+    RingFailed(String),
+}
+
+/// Hands out the scoops of the staggers of a set of plots as their reads complete.
+pub trait ReadStaggers {
+    /// Waits for the next read to complete, `None` once every stagger was read.
+    fn next<'b>(&'b mut self) -> Option<Completion<'b>>;
+
+    /// Stops reading a plot, staggers already in flight still come back.
+    fn drop_plot(&mut self, plot: usize);
+}
+
+/// Reads the scoops of every stagger of a set of plots with up to `QUEUE_DEPTH` reads in flight.
+///
+/// Staggers are requested from all plots in turn, so plots on different disks are read at the
+/// same time. They come back in the order the reads complete.
+pub struct StaggerReads<'a> {
+    ring: Ring,
+    plots: &'a [Plot],
+    files: Vec<Option<File>>,
+    queue: VecDeque<(usize, u64)>,
+    failed: VecDeque<(usize, String)>,
+    slots: Vec<Slot>,
+    free: Vec<usize>,
+    in_flight: usize,
+    /// the slot handed out by the last `next`, it is reused on the following call
+    lent: Option<usize>,
+    scoop_num: u16,
+    /// how often a failed read is tried again
+    retries: u32,
+}
+
+impl<'a> StaggerReads<'a> {
+    pub fn start(plots: &'a [Plot],
+                 scoop_num: u16,
+                 retries: u32)
+                 -> io::Result<StaggerReads<'a>> {
+        let ring = try!(Ring::new(QUEUE_DEPTH));
+
+        let mut files = Vec::new();
+        let mut failed = VecDeque::new();
+        for (i, plot) in plots.iter().enumerate() {
+            // buffered reads work too where O_DIRECT doesn't, the reads are aligned either way
+            match reader::open_direct(&plot.path).or_else(|_| File::open(&plot.path)) {
+                Ok(file) => files.push(Some(file)),
+                Err(e) => {
+                    failed.push_back((i, format!("opening: {}", e)));
+                    files.push(None);
+                }
+            }
+        }
+
+        let mut queue = VecDeque::new();
+        let stagger_counts: Vec<u64> = plots.iter()
+            .map(|plot| if plot.stagger_size == 0 {
+                0
+            } else {
+                plot.usable_nonces() / plot.stagger_size
+            })
+            .collect();
+        let most_staggers = stagger_counts.iter().cloned().max().unwrap_or(0);
+        for stagger in 0..most_staggers {
+            for (i, plot) in plots.iter().enumerate() {
+                let first_nonce = plot.start_nonce + stagger * plot.stagger_size;
+                if files[i].is_some() && stagger < stagger_counts[i] &&
+                   !plot.is_excluded(first_nonce, first_nonce + plot.stagger_size) {
+                    queue.push_back((i, stagger));
+                }
+            }
+        }
+
+        Ok(StaggerReads {
+            ring: ring,
+            plots: plots,
+            files: files,
+            queue: queue,
+            failed: failed,
+            slots: (0..QUEUE_DEPTH)
+                .map(|_| {
+                    Slot {
+                        iovec: libc::iovec {
+                            iov_base: ptr::null_mut(),
+                            iov_len: 0,
+                        },
+                        buffer: AlignedBuffer::new(),
+                        plot: 0,
+                        stagger: 0,
+                        scoops: (0, 0),
+                        retries: 0,
+                    }
+                })
+                .collect(),
+            free: (0..QUEUE_DEPTH as usize).collect(),
+            in_flight: 0,
+            lent: None,
+            scoop_num: scoop_num,
+            retries: retries,
+        })
+    }
+
+    fn push(&mut self, slot_index: usize, plot: usize, stagger: u64, retries: u32) {
+        let stagger_size = self.plots[plot].stagger_size;
+        let (offset, len) = reader::scoop_range(stagger_size, stagger, self.scoop_num);
+        let (read_start, read_len) = reader::direct_window(offset, len);
+        let fd = self.files[plot].as_ref().unwrap().as_raw_fd();
+
+        let slot = &mut self.slots[slot_index];
+        slot.buffer.resize(read_len);
+        slot.iovec = libc::iovec {
+            iov_base: slot.buffer.as_mut_slice().as_mut_ptr() as *mut libc::c_void,
+            iov_len: read_len,
+        };
+        slot.plot = plot;
+        slot.stagger = stagger;
+        slot.scoops = (offset - read_start, len);
+        slot.retries = retries;
+        // there are never more reads in flight than slots, and as many entries as slots
+        let queued = unsafe {
+            self.ring.push_read(fd, &slot.iovec, read_start as u64, slot_index as u64)
+        };
+        assert!(queued, "io_uring submission queue overflow");
+        self.in_flight += 1;
+    }
+
+    /// Sends a failed read out again while it has tries left.
+    fn retry(&mut self, slot_index: usize, res: i32) -> bool {
+        let (plot, stagger, retries) = {
+            let slot = &self.slots[slot_index];
+            (slot.plot, slot.stagger, slot.retries)
+        };
+        if res >= 0 || retries >= self.retries {
+            return false;
+        }
+        self.in_flight -= 1;
+        self.push(slot_index, plot, stagger, retries + 1);
+        true
+    }
+
+    fn complete<'b>(&'b mut self, slot_index: usize, res: i32) -> Completion<'b> {
+        self.in_flight -= 1;
+        let (plot, stagger, (start, len), retries) = {
+            let slot = &self.slots[slot_index];
+            (slot.plot, slot.stagger, slot.scoops, slot.retries)
+        };
+        if res < 0 {
+            self.free.push(slot_index);
+            return Completion::StaggerFailed {
+                plot: plot,
+                stagger: stagger,
+                error: format!("reading: {}", io::Error::from_raw_os_error(-res)),
+                retries: retries,
+            };
+        }
+        if (res as usize) < start + len {
+            self.free.push(slot_index);
+            self.drop_plot(plot);
+            return Completion::PlotFailed {
+                plot: plot,
+                error: String::from("past end of file"),
+            };
+        }
+        self.lent = Some(slot_index);
+        Completion::Scoops {
+            plot: plot,
+            stagger: stagger,
+            scoops: &self.slots[slot_index].buffer.as_slice()[start..start + len],
+            retries: retries,
+        }
+    }
+}
+
+impl<'a> ReadStaggers for StaggerReads<'a> {
+    fn next<'b>(&'b mut self) -> Option<Completion<'b>> {
+        if let Some(slot) = self.lent.take() {
+            self.free.push(slot);
+        }
+        if let Some((plot, error)) = self.failed.pop_front() {
+            return Some(Completion::PlotFailed {
+                plot: plot,
+                error: error,
+            });
+        }
+
+        while !self.queue.is_empty() && !self.free.is_empty() {
+            let (plot, stagger) = self.queue.pop_front().unwrap();
+            let slot = self.free.pop().unwrap();
+            self.push(slot, plot, stagger, 0);
+        }
+        if self.in_flight == 0 {
+            return None;
+        }
+
+        // new reads go out right away, so the disks work while we hash
+        let mut wait_for = 0;
+        loop {
+            if wait_for > 0 || self.ring.unsubmitted > 0 {
+                if let Err(e) = self.ring.submit_and_wait(wait_for) {
+                    self.queue.clear();
+                    return Some(Completion::RingFailed(format!("{}", e)));
+                }
+            }
+            wait_for = 1;
+            if let Some((user_data, res)) = self.ring.pop_completion() {
+                if self.retry(user_data as usize, res) {
+                    wait_for = 0;
+                    continue;
+                }
+                return Some(self.complete(user_data as usize, res));
+            }
+        }
+    }
+
+    fn drop_plot(&mut self, plot: usize) {
+        self.queue.retain(|&(queued_plot, _)| queued_plot != plot);
+    }
+}
+
+impl<'a> Drop for StaggerReads<'a> {
+    fn drop(&mut self) {
+        // the kernel writes into the slots until every read in flight completed
+        while self.in_flight > 0 {
+            while let Some(_) = self.ring.pop_completion() {
+                self.in_flight -= 1;
+            }
+            if self.in_flight > 0 && self.ring.submit_and_wait(1).is_err() {
+                mem::forget(mem::replace(&mut self.slots, Vec::new()));
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots::Plot;
+    use reader::MappedPlot;
+    use std::path::Path;
+
+    #[test]
+    fn reads_every_stagger_of_bundled_plots() {
+        if !supported() {
+            println!("skipped, io_uring is not available");
+            return;
+        }
+        let plots: Vec<Plot> = ["plots/15250033402865692318_0_8_2",
+                                "plots/15250033402865692318_8_8_2"]
+            .iter()
+            .map(|name| Plot::from_path(Path::new(name)).unwrap().unwrap())
+            .collect();
+        let mut mapped: Vec<MappedPlot> = plots.iter()
+            .map(|plot| MappedPlot::open(plot).unwrap())
+            .collect();
+
+        let mut reads = StaggerReads::start(&plots, 2570, 0).unwrap();
+        let mut seen = Vec::new();
+        while let Some(completion) = reads.next() {
+            match completion {
+                Completion::Scoops { plot, stagger, scoops, .. } => {
+                    assert_eq!(scoops, &mapped[plot].scoops(stagger, 2570).unwrap()[..]);
+                    seen.push((plot, stagger));
+                }
+                Completion::StaggerFailed { error, .. } |
+                Completion::PlotFailed { error, .. } |
+                Completion::RingFailed(error) => panic!("{}", error),
+            }
+        }
+        seen.sort();
+        assert_eq!(seen,
+                   vec![(0, 0), (0, 1), (0, 2), (0, 3), (1, 0), (1, 1), (1, 2), (1, 3)]);
+    }
+}
@@ -0,0 +1,121 @@
+use cli::Args;
+use constants::*;
+use plots::{self, Plot, PlotHealth};
+use rustc_serialize::json::Json;
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+/// `verify [--config <path>] [--sample <nonces>] [folder...]`
+///
+/// Scans the given folders, or the configured ones, and plots a few nonces of every plot again
+/// to compare all their scoops with what is on disk. Fails if any plot doesn't match.
+pub fn run(args: &[String]) -> Result<(), String> {
+    let mut args = try!(Args::parse(args, &["sample"]));
+    let sample = try!(args.option("sample", 4u64));
+    if sample == 0 {
+        return Err(String::from("--sample must be at least 1"));
+    }
+    // folders on the command line take the place of the configured ones
+    if !args.positional.is_empty() {
+        let folders = args.positional.iter().map(|folder| Json::String(folder.clone())).collect();
+        args.overrides.insert(String::from("plot_folders"), Json::Array(folders));
+    }
+    let miner_config = try!(args.load_config(&["plot_folders"]));
+    let folder_paths = miner_config.folder_paths().unwrap();
+
+    let plot_folders = plots::get_plots(folder_paths, &try!(miner_config.scan_options()));
+    for line in plot_folders.report() {
+        println!("{}", line);
+    }
+    let mut checked = 0;
+    let mut failed = 0;
+    for plot in plot_folders.folders.iter().flat_map(|folder| folder.plots.iter()) {
+        checked += 1;
+        match verify_plot(plot, sample) {
+            Ok(nonces) => println!("{:?}: {} nonces match", plot.path, nonces),
+            Err(e) => {
+                println!("{:?}: {}", plot.path, e);
+                failed += 1;
+            }
+        }
+    }
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} of {} plots failed", failed, checked)),
+    }
+}
+
+/// Plots up to `sample` nonces of `plot` again, spread over the part of it that can be mined,
+/// and compares every scoop of them with the file. Returns how many nonces were compared.
+pub fn verify_plot(plot: &Plot, sample: u64) -> Result<u64, String> {
+    if let PlotHealth::Unusable(ref reason) = plot.health {
+        return Err(reason.clone());
+    }
+    let nonces = sample_nonces(plot.start_nonce, plot.usable_nonces(), sample);
+    let file = try!(File::open(&plot.path).map_err(|e| format!("opening: {}", e)));
+    let mut on_disk = [0u8; HASH_SIZE * 2];
+    for &nonce in &nonces {
+        let plotted = plots::plot_nonce(plot.account_id, nonce);
+        for (scoop, expected) in plotted.chunks(HASH_SIZE * 2).enumerate() {
+            let offset = plot.scoop_offset(nonce, scoop as u16).unwrap();
+            try!(file.read_exact_at(&mut on_disk, offset)
+                .map_err(|e| format!("reading nonce {} scoop {}: {}", nonce, scoop, e)));
+            if &on_disk[..] != expected {
+                return Err(format!("nonce {} scoop {} doesn't match", nonce, scoop));
+            }
+        }
+    }
+    Ok(nonces.len() as u64)
+}
+
+/// The first and the last nonce and evenly spaced ones between them.
+fn sample_nonces(start: u64, count: u64, sample: u64) -> Vec<u64> {
+    if count <= sample {
+        return (start..start + count).collect();
+    }
+    if sample == 1 {
+        return vec![start];
+    }
+    (0..sample).map(|i| start + i * (count - 1) / (sample - 1)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plots;
+    use std::fs::{self, OpenOptions};
+    use std::os::unix::fs::FileExt;
+    use std::path::Path;
+    use testing::TempDir;
+
+    #[test]
+    fn samples_spread_over_the_plot() {
+        assert_eq!(sample_nonces(8, 8, 4), vec![8, 10, 12, 15]);
+        assert_eq!(sample_nonces(8, 3, 4), vec![8, 9, 10]);
+        assert_eq!(sample_nonces(8, 8, 1), vec![8]);
+    }
+
+    #[test]
+    fn finds_corrupted_scoops() {
+        let dir = TempDir::new("verify");
+        let name = "15250033402865692318_8_8_2";
+        fs::copy(Path::new("plots").join(name), dir.join(name)).unwrap();
+        let plot = plots::get_plots(vec![dir.path().to_str().unwrap().to_owned()],
+                                    &Default::default())
+            .folders
+            .remove(0)
+            .plots
+            .remove(0);
+        assert_eq!(verify_plot(&plot, 2), Ok(2));
+
+        // the second nonce of the last stagger, scoop 1614
+        let offset = plot.scoop_offset(15, 1614).unwrap();
+        OpenOptions::new()
+            .write(true)
+            .open(&plot.path)
+            .unwrap()
+            .write_all_at(&[0xff; 4], offset)
+            .unwrap();
+        assert_eq!(verify_plot(&plot, 2), Err(String::from("nonce 15 scoop 1614 doesn't match")));
+    }
+}